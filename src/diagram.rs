@@ -0,0 +1,196 @@
+use na::{DMatrix, DVector};
+
+use crate::dipolar::{DdKernel, DipolarSystem, Solver, generate_dd_int_mat, generate_dd_int_mat_fft, get_dd_kernel,
+                     get_mu_inequality, solve_tunneling};
+use crate::energy::get_energy;
+use crate::error::{Error, Result};
+use crate::lattice_sum::get_dd_kernel_lattice_sum;
+use crate::lattice::{PeriodicLattice, fill_pattern, get_checkerboard, get_filled,
+                     get_horizontal_stripe};
+use crate::util;
+
+/// mu window and resolution of a phase diagram
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DiagramParams {
+    pub mu_range: (f64, f64),
+    /// number of mu points, the lobe edges inside the window are added
+    pub no_points: usize,
+    /// largest occupation of the candidate patterns
+    pub n_max: u8,
+}
+
+impl DiagramParams {
+    pub fn new(mu_range: (f64, f64), no_points: usize, n_max: u8) -> Self {
+        DiagramParams { mu_range, no_points, n_max }
+    }
+}
+
+/// Candidate occupation of a phase diagram
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Candidate {
+    /// name used in the output, e.g. "cb_2_1"
+    pub label: String,
+    pub occupation: DMatrix<u8>,
+}
+
+impl Candidate {
+    pub fn new(label: String, occupation: DMatrix<u8>) -> Self {
+        Candidate { label, occupation }
+    }
+}
+
+/// Critical tunneling of one candidate inside its stability range
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Lobe {
+    /// index into the candidates
+    pub pattern: usize,
+    /// mu range in which the occupation is stable, see get_mu_inequality
+    pub stable_range: (f64, f64),
+    pub mu: DVector<f64>,
+    pub tunneling: DVector<f64>,
+}
+
+/// Phase boundary assembled from the lobes of several candidates
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PhaseDiagram {
+    /// lobes of the candidates which are stable somewhere in the window
+    pub lobes: Vec<Lobe>,
+    /// mu points of the boundary
+    pub mu: DVector<f64>,
+    /// critical tunneling of the lobe of the atomic limit ground state,
+    /// 0 if no candidate is stable at mu
+    pub tunneling: DVector<f64>,
+    /// stable candidate with the lowest atomic limit energy at mu
+    pub pattern: Vec<Option<usize>>,
+    /// largest critical tunneling of the lobes containing mu, which can
+    /// belong to a metastable candidate
+    pub max_tunneling: DVector<f64>,
+    /// candidate with the largest critical tunneling at mu
+    pub max_pattern: Vec<Option<usize>>,
+}
+
+/// Get the uniform, checkerboard and stripe patterns with fillings up to n_max
+pub fn get_candidate_patterns(latt: &PeriodicLattice, n_max: u8) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = (0..=n_max)
+        .map(|n| Candidate::new(format!("filled_{n}"), get_filled(latt)*n))
+        .collect();
+
+    for n_a in 1..=n_max {
+        for n_b in 0..n_a {
+            candidates.push(Candidate::new(format!("cb_{n_a}_{n_b}"),
+                                           fill_pattern(&get_checkerboard(latt), (n_a, n_b))));
+            candidates.push(Candidate::new(format!("hstripe_{n_a}_{n_b}"),
+                                           fill_pattern(&get_horizontal_stripe(latt), (n_a, n_b))));
+        }
+    }
+
+    candidates
+}
+
+/// Get the mu points of the diagram, no_points evenly spaced points
+/// of the window together with the lobe edges inside it, so that
+/// adjacent lobes meet at t = 0
+fn get_diagram_mu(params: &DiagramParams, stable_ranges: &[(f64, f64)]) -> DVector<f64> {
+    let (mu_start, mu_end) = params.mu_range;
+
+    let mut mu_vals: Vec<f64> = util::linspace(mu_start, mu_end, params.no_points, true)
+        .iter()
+        .copied()
+        .chain(stable_ranges.iter().flat_map(|&(lower, upper)| [lower, upper]))
+        .filter(|mu| (mu_start..=mu_end).contains(mu))
+        .collect();
+    mu_vals.sort_by(|a, b| a.total_cmp(b));
+    mu_vals.dedup_by(|a, b| (*a - *b).abs() < 1e-12);
+
+    DVector::from_vec(mu_vals)
+}
+
+/// Assemble the phase boundary of the candidates in the mu window
+///
+/// Every candidate is solved with solver at the diagram mu points inside
+/// its stability range. Where lobes overlap the boundary follows the
+/// lobe of the atomic limit ground state, and the lobe with the largest
+/// critical tunneling is recorded separately. The lobe edges are part of
+/// the mu points, so adjacent lobes close at t = 0. The mu points of a
+/// lobe are evaluated in parallel on num_threads threads.
+///
+/// With lattice_sum the d-d interaction is the complete lattice sum,
+/// see get_dd_kernel_lattice_sum, instead of being truncated at the
+/// interaction range.
+///
+/// Returns an error if the occupation of a candidate does not fit the
+/// lattice, or with lattice_sum if the lattice boundary is not periodic.
+pub fn get_phase_diagram(candidates: &[Candidate], dip: &DipolarSystem, params: &DiagramParams,
+                         solver: &Solver, lattice_sum: bool, num_threads: usize) -> Result<PhaseDiagram> {
+    let mut work = dip.clone();
+    let kernel = if lattice_sum {
+        if !dip.latt.boundary.is_periodic() {
+            return Err(Error::Unsupported("the lattice sum requires periodic boundaries"))
+        }
+        Some(DdKernel::new(get_dd_kernel_lattice_sum(dip, 1e-8), &dip.latt)?)
+    } else {
+        get_dd_kernel(&work).ok()
+    };
+
+    let mut systems = Vec::with_capacity(candidates.len());
+    for candidate in candidates {
+        work.update_occupation(candidate.occupation.clone())?;
+        match &kernel {
+            Some(kernel) => generate_dd_int_mat_fft(&mut work, kernel)?,
+            None => generate_dd_int_mat(&mut work),
+        }
+        systems.push(work.clone());
+    }
+
+    let stable_ranges: Vec<(f64, f64)> = systems.iter().map(get_mu_inequality).collect();
+    let mu_vals = get_diagram_mu(params, &stable_ranges);
+
+    let mut max_tunneling = DVector::zeros(mu_vals.len());
+    let mut max_pattern = vec![None; mu_vals.len()];
+    // candidate, atomic limit energy and critical tunneling of the ground state
+    let mut ground_state: Vec<Option<(usize, f64, f64)>> = vec![None; mu_vals.len()];
+    let mut lobes = Vec::new();
+
+    for (idx, (system, &(lower, upper))) in systems.iter().zip(&stable_ranges).enumerate() {
+        let points: Vec<usize> = (0..mu_vals.len())
+            .filter(|&point| lower <= mu_vals[point] && mu_vals[point] <= upper)
+            .collect();
+        if points.is_empty() {
+            continue
+        }
+
+        let lobe_mu = DVector::from_iterator(points.len(), points.iter().map(|&point| mu_vals[point]));
+        let lobe_tunneling = util::parallel_map(&lobe_mu, num_threads, |mu| solve_tunneling(mu, system, solver));
+
+        for (&point, &t_c) in points.iter().zip(lobe_tunneling.iter()) {
+            if max_pattern[point].is_none() || t_c > max_tunneling[point] {
+                max_tunneling[point] = t_c;
+                max_pattern[point] = Some(idx);
+            }
+
+            let energy = get_energy(mu_vals[point], system).total();
+            if ground_state[point].is_none_or(|(_, lowest, _)| energy < lowest) {
+                ground_state[point] = Some((idx, energy, t_c));
+            }
+        }
+
+        lobes.push(Lobe { pattern: idx, stable_range: (lower, upper),
+                          mu: lobe_mu, tunneling: lobe_tunneling });
+    }
+
+    let tunneling = DVector::from_iterator(mu_vals.len(), ground_state.iter()
+        .map(|state| state.map_or(0., |(_, _, t_c)| t_c)));
+
+    Ok(PhaseDiagram {
+        lobes,
+        mu: mu_vals,
+        tunneling,
+        pattern: ground_state.into_iter().map(|state| state.map(|(idx, _, _)| idx)).collect(),
+        max_tunneling,
+        max_pattern,
+    })
+}
@@ -1,10 +1,23 @@
-use std::{path::Path, time::Instant};
+use std::{collections::HashMap, f64::consts::PI, fs, path::{Path, PathBuf}, time::Instant};
 
-use na::{Vector3, DMatrix};
+use na::{Complex, Vector3, DMatrix};
+use rustfft::FftDirection;
 use nalgebra::{DVector};
 
-use crate::lattice::{PeriodicLattice, SpinIdx, LattPos, get_checkerboard, get_filled, get_horizontal_stripe};
+use crate::lattice::{Boundary, Geometry, PeriodicLattice, Stacking, SpinIdx, LattPos, fill_pattern,
+                     get_checkerboard, get_filled, get_harmonic_potential, get_horizontal_stripe, get_next_lobe};
 use crate::util;
+use crate::fft::Fft2;
+use crate::error::{Error, Result};
+use crate::disorder::{Disorder, apply_disorder};
+use crate::diagram::{Candidate, DiagramParams, get_candidate_patterns, get_phase_diagram};
+use crate::lattice_sum::get_dd_kernel_lattice_sum;
+use crate::momentum::get_tunneling_momentum;
+use crate::phase::{PhaseMapGrid, classify_density, get_phase_map};
+use crate::scaling::{FiniteSizeFit, LobeTip, fit_finite_size, get_lobe_tip};
+use crate::search::{AnnealingSchedule, EnumerationParams, enumerate_patterns, find_ground_state,
+                    get_canonical_occupation, get_ground_state_ranges};
+use crate::sparse::get_tunneling_sparse;
 
 /// Get the dipole-dipole interaction
 /// 
@@ -14,14 +27,49 @@ pub fn get_dd_int(dist_v: Vector3<f64>, dip_v: Vector3<f64>)
 -> f64 {
     let dist = dist_v.norm();
 
-    let dd_int = (1.-3.*(dist_v.dot(&dip_v)/dist).powi(2))
-        /(dist.powi(3));
+    (1.-3.*(dist_v.dot(&dip_v)/dist).powi(2))
+        /(dist.powi(3))
+}
+
+/// Tunneling amplitudes relative to the reference tunneling t
+///
+/// A nearest neighbour bond along the unit vector d has the amplitude
+/// tx*d_x^2 + ty*d_y^2 + tz*d_z^2, so on the square lattice tx and ty
+/// are the amplitudes along x and y and tz the one between layers.
+/// Next-nearest neighbours within a layer have the amplitude t_nnn.
+/// The critical tunneling is the critical value of t.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct Hopping {
+    pub tx: f64,
+    pub ty: f64,
+    pub tz: f64,
+    pub t_nnn: f64,
+}
+
+impl Hopping {
+    pub fn new(tx: f64, ty: f64, tz: f64, t_nnn: f64) -> Self {
+        Hopping { tx, ty, tz, t_nnn }
+    }
+
+    /// Get the amplitude of the nearest neighbour bond along bond_vec
+    pub fn get_amplitude(&self, bond_vec: &Vector3<f64>) -> f64 {
+        let d = bond_vec.normalize();
+
+        self.tx*d.x*d.x + self.ty*d.y*d.y + self.tz*d.z*d.z
+    }
+}
 
-    dd_int
+impl Default for Hopping {
+    /// Isotropic nearest neighbour tunneling
+    fn default() -> Self {
+        Self::new(1., 1., 1., 0.)
+    }
 }
 
 /// Struct holding info about the dipolar system parameters
 #[non_exhaustive]
+#[derive(Clone)]
 pub struct DipolarSystem {
     pub theta: f64,
     pub phi: f64,
@@ -30,6 +78,17 @@ pub struct DipolarSystem {
     pub latt: PeriodicLattice,
     pub occupation: DMatrix<u8>,
     pub dd_mat: DMatrix<f64>,
+    /// site-dependent potential, the local chemical potential is mu - potential
+    pub potential: DMatrix<f64>,
+    /// tunneling of bonds relative to t, keyed by the ordered spin
+    /// indices of the two sites, bonds which are not present have 1
+    pub bond_tunneling: HashMap<(usize, usize), f64>,
+    /// tunneling amplitudes of the bond directions
+    pub hopping: Hopping,
+    /// occupation dependence g of the tunneling, a particle hops with
+    /// b_i^+ (1 + g n_i)(1 + g n_j) b_j, which is the bond-charge
+    /// tunneling 1 + g (n_i + n_j) to first order in g
+    pub bond_charge: f64,
 }
 
 impl DipolarSystem {
@@ -39,51 +98,165 @@ impl DipolarSystem {
                      self.theta.cos())
     }
 
+    /// Create a system on a square lattice
+    ///
+    /// Returns an error if the lattice cannot be created, see PeriodicLattice::new.
     pub fn new(theta: f64, phi: f64, u_onsite: f64, 
                int_range: usize, system_size: usize
-               ) -> Self {
+               ) -> Result<Self> {
+
+        Self::new_rect(theta, phi, u_onsite, int_range, system_size, system_size)
+    }
+
+    /// Create a system on a rectangular size_x x size_y lattice, see new
+    pub fn new_rect(theta: f64, phi: f64, u_onsite: f64,
+                    int_range: usize, size_x: usize, size_y: usize
+                    ) -> Result<Self> {
+
+        Ok(Self::from_lattice(theta, phi, u_onsite, int_range,
+                              PeriodicLattice::new_rect(size_x, size_y)?))
+    }
 
-        let latt = PeriodicLattice::new(system_size);
+    /// Create a system on the given lattice
+    pub fn from_lattice(theta: f64, phi: f64, u_onsite: f64,
+                        int_range: usize, latt: PeriodicLattice
+                        ) -> Self {
 
-        let occupation = DMatrix::zeros(system_size, system_size);
-        let dd_mat = DMatrix::zeros(system_size, system_size);
-        DipolarSystem { theta, phi, u_onsite, int_range, latt, occupation, dd_mat}
+        let occupation = DMatrix::zeros(latt.size_y, latt.ncols());
+        let dd_mat = DMatrix::zeros(latt.size_y, latt.ncols());
+        let potential = DMatrix::zeros(latt.size_y, latt.ncols());
+        DipolarSystem { theta, phi, u_onsite, int_range, latt, occupation, dd_mat, potential,
+                        bond_tunneling: HashMap::new(), hopping: Hopping::default(), bond_charge: 0. }
     }
 
-    pub fn update_occupation(&mut self, occupation: DMatrix<u8>) {
-        assert!(occupation.nrows() == self.latt.system_size, "occupation nrows != system_size");
-        
-        assert!(occupation.ncols() == self.latt.system_size, "occupation ncols != system_size");
+    /// Set the occupation
+    ///
+    /// Returns an error if it is not a latt.size_y x latt.ncols() matrix.
+    pub fn update_occupation(&mut self, occupation: DMatrix<u8>) -> Result<()> {
+        self.check_shape("occupation", occupation.shape())?;
+
+        self.occupation = occupation;
+        Ok(())
+    }
+
+    /// Set the site-dependent potential, see update_occupation
+    pub fn update_potential(&mut self, potential: DMatrix<f64>) -> Result<()> {
+        self.check_shape("potential", potential.shape())?;
+
+        self.potential = potential;
+        Ok(())
+    }
+
+    fn check_shape(&self, name: &'static str, shape: (usize, usize)) -> Result<()> {
+        let expected = (self.latt.size_y, self.latt.ncols());
+
+        if shape != expected {
+            return Err(Error::Shape { name, expected, found: shape })
+        }
+        Ok(())
+    }
+
+    /// Get the tunneling of the bond between spin indices idx and idx_n relative to t
+    pub fn get_bond_tunneling(&self, idx: usize, idx_n: usize) -> f64 {
+        if self.bond_tunneling.is_empty() {
+            return 1.
+        }
+        *self.bond_tunneling.get(&(idx.min(idx_n), idx.max(idx_n))).unwrap_or(&1.)
+    }
+
+    /// Get the hoppings of cell site s as (n1, n2, s_n) cell offsets
+    /// and cell site index with the amplitude of hopping
+    ///
+    /// Bonds with zero amplitude are left out.
+    pub fn get_hopping_offsets(&self, s: usize) -> Vec<(isize, isize, usize, f64)> {
+        let latt = &self.latt;
+
+        let nearest = latt.neighbour_offsets()[s].iter()
+            .map(|&(n1, n2, s_n)| (n1, n2, s_n, self.hopping.get_amplitude(&latt.get_bond_vec(s, n1, n2, s_n))));
+        let next_nearest = latt.nnn_offsets()[s].iter()
+            .filter(|_| self.hopping.t_nnn != 0.)
+            .map(|&(n1, n2, s_n)| (n1, n2, s_n, self.hopping.t_nnn));
+
+        nearest.chain(next_nearest)
+            .filter(|&(_, _, _, amplitude)| amplitude != 0.)
+            .collect()
+    }
+
+    /// Get the sites (x_n, y_n) site (y, x) hops to together with the
+    /// tunneling of the bond relative to t, from hopping, bond_tunneling
+    /// and the sign of twisted boundaries, see get_twist_sign
+    ///
+    /// Every site appears once, also where several hops wrap onto the
+    /// same site, e.g. both neighbours along x on a lattice of size 2.
+    pub fn get_hoppings(&self, x: usize, y: usize) -> Vec<(usize, usize, f64)> {
+        let idx = self.latt.get_spin_idx(x, y);
+        let mut hoppings: Vec<(usize, usize, f64)> = Vec::new();
+
+        for (n1, n2, s_n, amplitude) in self.get_hopping_offsets(x%self.latt.n_cell_sites()) {
+            let Some((x_n, y_n)) = self.latt.get_offset_pos(x, y, n1, n2, s_n) else { continue };
+
+            if hoppings.iter().all(|&(x_h, y_h, _)| (x_h, y_h) != (x_n, y_n)) {
+                let idx_n = self.latt.get_spin_idx(x_n, y_n);
+                hoppings.push((x_n, y_n, amplitude*self.get_bond_tunneling(idx, idx_n)
+                               *self.latt.get_twist_sign(x, y, n1, n2)));
+            }
+        }
+
+        hoppings
+    }
 
-        self.occupation = occupation
+    /// Whether the potential is the same on every site, so that the
+    /// system is translation invariant up to its occupation
+    pub fn has_uniform_potential(&self) -> bool {
+        self.potential.iter().all(|&v| v == self.potential[(0, 0)])
     }
 }
 
 /// Get the dipole dipole interaction
 /// a particle would experience if added to site (y, x)
-/// Assume that occupation is a latt.system_size x latt.system_size
+/// Assume that occupation is a latt.size_y x latt.ncols()
 /// matrix.
 pub fn get_dd_int_site(x: isize, y: isize,
                        dip: &DipolarSystem) -> f64 {
 
+    get_dd_field_site(x, y, &dip.occupation, dip)
+}
+
+/// Get the dipole dipole interaction at site (y, x)
+/// with a given density, e.g. a mean occupation
+pub fn get_dd_field_site<T>(x: isize, y: isize, density: &DMatrix<T>,
+                            dip: &DipolarSystem) -> f64
+where T: na::Scalar + Copy + Into<f64> {
+
     let mut interaction: f64 = 0.;
-    let int_range_cast = isize::try_from(dip.int_range).unwrap();
+    let latt = &dip.latt;
+    let n_cell_sites = latt.n_cell_sites();
+    let s = x.rem_euclid(n_cell_sites as isize) as usize;
+    let (n1_max, n2_max) = latt.geometry.get_cell_window(dip.int_range as f64);
+    let n3_max = latt.get_image_window(dip.int_range as f64);
+    let (a1, a2) = latt.geometry.primitive_vectors();
+    let a3 = Vector3::new(0., 0., latt.period_z());
+    let cell_sites = latt.cell_sites();
 
     // go over neighbors
-    for x_n in x-int_range_cast..x+int_range_cast + 1 {
-        for y_n in y-int_range_cast..y+int_range_cast + 1 {
-            // get periodic indices
-            let x_n_p = dip.latt.get_idx_periodic(x_n);
-            let y_n_p = dip.latt.get_idx_periodic(y_n);
+    for n1 in -n1_max..n1_max + 1 {
+        for n2 in -n2_max..n2_max + 1 {
+            for s_n in 0..n_cell_sites {
+                // get periodic indices, skip sites outside open boundaries
+                let Some((x_n_p, y_n_p)) = latt.get_offset_pos(x as usize, y as usize, n1, n2, s_n)
+                else { continue };
 
-            let dist_vec =  Vector3::new(x as f64-x_n as f64,
-                                         y as f64 -y_n as f64,
-                                         0.);
+                // periodic images along z
+                for n3 in -n3_max..n3_max + 1 {
+                    let dist_vec = a1*n1 as f64 + a2*n2 as f64 + a3*n3 as f64
+                        + cell_sites[s_n] - cell_sites[s];
 
-            let dist = dist_vec.norm();
+                    let dist = dist_vec.norm();
 
-            if !(x_n_p == x as usize && y_n_p == y as usize) && dist<=dip.int_range as f64 {
-                interaction += dip.occupation[(y_n_p, x_n_p)] as f64 * get_dd_int(dist_vec, dip.get_dipole_vec());
+                    if !(x_n_p == x as usize && y_n_p == y as usize) && dist<=dip.int_range as f64 {
+                        interaction += density[(y_n_p, x_n_p)].into() * get_dd_int(dist_vec, dip.get_dipole_vec());
+                    }
+                }
             }
         }
     }
@@ -92,17 +265,17 @@ pub fn get_dd_int_site(x: isize, y: isize,
 }
 
 // Generate the d-d interaction matrix
-/// Assume that occupation is a latt.system_size x latt.system_size
+/// Assume that occupation is a latt.size_y x latt.ncols()
 /// matrix.
 pub fn generate_dd_int_mat(dip: &mut DipolarSystem) {
 
-    let l = dip.latt.system_size;
+    let latt = &dip.latt;
     let mut dd_mat: DMatrix<f64> 
-        = DMatrix::zeros(l, l);
-    for x in 0..l {
-        for y in 0..l {
+        = DMatrix::zeros(latt.size_y, latt.ncols());
+    for x in 0..latt.ncols() {
+        for y in 0..latt.size_y {
             // x, y guarnateed to be isize as
-            // l is < isize::MAX
+            // the number of sites is < isize::MAX
             dd_mat[(y, x)] = get_dd_int_site(x as isize, y as isize, dip);
         }
     }
@@ -110,12 +283,247 @@ pub fn generate_dd_int_mat(dip: &mut DipolarSystem) {
     dip.dd_mat = dd_mat;
 }
 
+/// Periodic d-d interaction kernel together with its Fourier transform
+///
+/// The kernel has a size_y x size_x block for every pair of cell sites,
+/// blocks[s*n_cell_sites + s_n], see get_dd_kernel. The blocks are
+/// transformed once, so that a convolution only transforms the density.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct DdKernel {
+    pub blocks: Vec<DMatrix<f64>>,
+    n_cell_sites: usize,
+    fft: Fft2,
+    blocks_k: Vec<DMatrix<Complex<f64>>>,
+}
+
+impl DdKernel {
+    /// Create the kernel of the lattice from its blocks
+    ///
+    /// Returns an error if there are not n_cell_sites^2 blocks of
+    /// size_y x size_x.
+    pub fn new(blocks: Vec<DMatrix<f64>>, latt: &PeriodicLattice) -> Result<Self> {
+        let n_cell_sites = latt.n_cell_sites();
+        let expected = (latt.size_y, latt.size_x);
+
+        if blocks.len() != n_cell_sites*n_cell_sites {
+            return Err(Error::Shape { name: "d-d kernel", expected: (n_cell_sites*n_cell_sites, 1),
+                                      found: (blocks.len(), 1) })
+        }
+        if let Some(block) = blocks.iter().find(|block| block.shape() != expected) {
+            return Err(Error::Shape { name: "d-d kernel block", expected, found: block.shape() })
+        }
+
+        let fft = Fft2::new(latt.size_y, latt.size_x);
+        let blocks_k = blocks.iter()
+            .map(|block| {
+                let mut block_k = block.map(|v| Complex::new(v, 0.));
+                fft.process(&mut block_k, FftDirection::Forward);
+                block_k
+            })
+            .collect();
+
+        Ok(DdKernel { blocks, n_cell_sites, fft, blocks_k })
+    }
+
+    /// Whether the kernel belongs to a lattice of the same size and cell
+    pub fn fits(&self, latt: &PeriodicLattice) -> bool {
+        self.fft.shape() == (latt.size_y, latt.size_x) && self.n_cell_sites == latt.n_cell_sites()
+    }
+}
+
+/// Get the periodic d-d interaction kernel of the system
+///
+/// The kernel has a size_y x size_x block for every pair of cell
+/// sites, kernel[s*n_cell_sites + s_n]. Its element (ky, kx) is the summed
+/// interaction of cell site s with the cell sites s_n within
+/// int_range whose cell offset is (-kx, -ky) modulo the lattice size,
+/// excluding the periodic images of the site itself. The kernel only
+/// depends on theta, phi, int_range and the lattice, so it can be
+/// reused between occupations.
+///
+/// Returns an error if the lattice boundary is not periodic.
+pub fn get_dd_kernel(dip: &DipolarSystem) -> Result<DdKernel> {
+    let latt = &dip.latt;
+    if !latt.boundary.is_periodic() {
+        return Err(Error::Unsupported("the d-d kernel requires periodic boundaries"))
+    }
+    let n_cell_sites = latt.n_cell_sites();
+    let (n1_max, n2_max) = latt.geometry.get_cell_window(dip.int_range as f64);
+    let n3_max = latt.get_image_window(dip.int_range as f64);
+    let (a1, a2) = latt.geometry.primitive_vectors();
+    let a3 = Vector3::new(0., 0., latt.period_z());
+    let cell_sites = latt.cell_sites();
+    let dip_vec = dip.get_dipole_vec();
+
+    let mut kernel = vec![DMatrix::zeros(latt.size_y, latt.size_x); n_cell_sites*n_cell_sites];
+
+    for s in 0..n_cell_sites {
+        for s_n in 0..n_cell_sites {
+            for n1 in -n1_max..n1_max + 1 {
+                for n2 in -n2_max..n2_max + 1 {
+                    let kx = (-n1).rem_euclid(latt.size_x as isize) as usize;
+                    let ky = latt.get_y_periodic(-n2);
+
+                    for n3 in -n3_max..n3_max + 1 {
+                        let dist_vec = a1*n1 as f64 + a2*n2 as f64 + a3*n3 as f64
+                            + cell_sites[s_n] - cell_sites[s];
+
+                        if !(kx == 0 && ky == 0 && s == s_n) && dist_vec.norm() <= dip.int_range as f64 {
+                            kernel[s*n_cell_sites + s_n][(ky, kx)] += get_dd_int(dist_vec, dip_vec);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    DdKernel::new(kernel, latt)
+}
+
+/// Get the d-d interaction of every cell site s with the sites
+/// within int_range as (n1, n2, s_n, interaction), with the cell
+/// offset (n1, n2), the cell site s_n and the periodic images along
+/// z summed. Offsets which wrap back onto the site itself need to be
+/// skipped, as in get_dd_field_site.
+///
+/// Used to update the d-d interaction after changing the occupation
+/// of a single site.
+pub fn get_dd_offsets(dip: &DipolarSystem) -> Vec<Vec<(isize, isize, usize, f64)>> {
+    let latt = &dip.latt;
+    let n_cell_sites = latt.n_cell_sites();
+    let (n1_max, n2_max) = latt.geometry.get_cell_window(dip.int_range as f64);
+    let n3_max = latt.get_image_window(dip.int_range as f64);
+    let (a1, a2) = latt.geometry.primitive_vectors();
+    let a3 = Vector3::new(0., 0., latt.period_z());
+    let cell_sites = latt.cell_sites();
+    let dip_vec = dip.get_dipole_vec();
+
+    (0..n_cell_sites).map(|s| {
+        let mut offsets = Vec::new();
+
+        for n1 in -n1_max..n1_max + 1 {
+            for n2 in -n2_max..n2_max + 1 {
+                for s_n in 0..n_cell_sites {
+                    let interaction: f64 = (-n3_max..n3_max + 1)
+                        .map(|n3| a1*n1 as f64 + a2*n2 as f64 + a3*n3 as f64 + cell_sites[s_n] - cell_sites[s])
+                        .filter(|dist_vec| dist_vec.norm() > 0. && dist_vec.norm() <= dip.int_range as f64)
+                        .map(|dist_vec| get_dd_int(dist_vec, dip_vec))
+                        .sum();
+
+                    if interaction != 0. {
+                        offsets.push((n1, n2, s_n, interaction));
+                    }
+                }
+            }
+        }
+        offsets
+    }).collect()
+}
+
+/// Periodic convolution of the occupation with a kernel
+/// of the form returned by get_dd_kernel
+///
+/// Returns an error if the lattice boundary is not periodic.
+pub fn convolve_dd_kernel(dip: &DipolarSystem, kernel: &DdKernel) -> Result<DMatrix<f64>> {
+    get_dd_field_fft(&dip.occupation.map(|n| n as f64), dip, kernel)
+}
+
+/// Get the d-d interaction matrix of a given density
+/// by direct summation, see get_dd_field_site
+pub fn get_dd_field(density: &DMatrix<f64>, dip: &DipolarSystem) -> DMatrix<f64> {
+    DMatrix::from_fn(dip.latt.size_y, dip.latt.ncols(),
+                     |y, x| get_dd_field_site(x as isize, y as isize, density, dip))
+}
+
+/// Periodic convolution of a given density with a kernel
+/// of the form returned by get_dd_kernel
+///
+/// Every cell site of the density is transformed once, and the
+/// interaction of cell site s is the inverse transform of the sum
+/// over s_n of the products with the kernel blocks.
+///
+/// Returns an error if the lattice boundary is not periodic or the
+/// kernel does not belong to the lattice.
+pub fn get_dd_field_fft(density: &DMatrix<f64>, dip: &DipolarSystem,
+                        kernel: &DdKernel) -> Result<DMatrix<f64>> {
+    let latt = &dip.latt;
+    if !latt.boundary.is_periodic() {
+        return Err(Error::Unsupported("the d-d kernel requires periodic boundaries"))
+    }
+    if !kernel.fits(latt) {
+        return Err(Error::Shape { name: "d-d kernel", expected: (latt.size_y, latt.size_x),
+                                  found: kernel.fft.shape() })
+    }
+
+    let n_cell_sites = latt.n_cell_sites();
+
+    let occupation_k: Vec<DMatrix<Complex<f64>>> = (0..n_cell_sites)
+        .map(|s| {
+            let mut occupation = DMatrix::from_fn(latt.size_y, latt.size_x,
+                                                  |y, cell_x| Complex::new(density[(y, cell_x*n_cell_sites + s)], 0.));
+            kernel.fft.process(&mut occupation, FftDirection::Forward);
+            occupation
+        })
+        .collect();
+
+    let mut dd_mat = DMatrix::zeros(latt.size_y, latt.ncols());
+
+    for s in 0..n_cell_sites {
+        let mut dd_s = DMatrix::zeros(latt.size_y, latt.size_x);
+        for (occupation_s_n, block_k) in occupation_k.iter().zip(&kernel.blocks_k[s*n_cell_sites..]) {
+            dd_s += occupation_s_n.component_mul(block_k);
+        }
+        kernel.fft.process(&mut dd_s, FftDirection::Inverse);
+
+        for cell_x in 0..latt.size_x {
+            dd_mat.column_mut(cell_x*n_cell_sites + s).copy_from(&dd_s.column(cell_x).map(|v| v.re));
+        }
+    }
+
+    Ok(dd_mat)
+}
+
+/// Generate the d-d interaction matrix as the periodic convolution
+/// of the occupation with the kernel from get_dd_kernel using FFTs
+///
+/// Gives the same result as generate_dd_int_mat up to rounding, in
+/// O(L^2 log L) instead of O(L^2 R^2).
+///
+/// Returns an error if the lattice boundary is not periodic.
+pub fn generate_dd_int_mat_fft(dip: &mut DipolarSystem, kernel: &DdKernel) -> Result<()> {
+    dip.dd_mat = convolve_dd_kernel(dip, kernel)?;
+    Ok(())
+}
+
 pub fn get_particle_e(x: usize, y: usize, mu: f64, dip: &DipolarSystem) -> f64 {
-    -mu + dip.u_onsite*(dip.occupation[(y, x)] as f64) + dip.dd_mat[(y, x)]
+    -mu + dip.potential[(y, x)] + dip.u_onsite*(dip.occupation[(y, x)] as f64) + dip.dd_mat[(y, x)]
 }
 
 pub fn get_hole_e(x: usize, y: usize, mu: f64, dip: &DipolarSystem) -> f64 { 
-    mu - dip.u_onsite*(dip.occupation[(y, x)] as f64 - 1.) - dip.dd_mat[(y, x)]
+    mu - dip.potential[(y, x)] - dip.u_onsite*(dip.occupation[(y, x)] as f64 - 1.) - dip.dd_mat[(y, x)]
+}
+
+/// Get the off-diagonal coefficient of the matrix M row of site (y, x)
+/// per unit tunneling, i.e. the row value is t*get_m_coeff
+///
+/// With bond-charge tunneling the particle and hole processes of the
+/// site carry the factors 1 + bond_charge*n and 1 + bond_charge*(n - 1),
+/// which enter squared as the order parameter carries the same factors.
+pub fn get_m_coeff(x: usize, y: usize, mu: f64, dip: &DipolarSystem) -> f64 {
+    let n = dip.occupation[(y, x)] as f64;
+
+    let particle_e = get_particle_e(x, y, mu, dip);
+    let hole_e = get_hole_e(x, y, mu, dip);
+
+    let particle_amp = 1. + dip.bond_charge*n;
+    let hole_amp = 1. + dip.bond_charge*(n - 1.);
+
+    if particle_e==0. || hole_e==0. {
+        f64::INFINITY
+    } else {
+        -((n + 1.)*particle_amp.powi(2)/particle_e + n*hole_amp.powi(2)/hole_e)
+    }
 }
 
 /// Generate Matrix M row from Trefzger et al., J. Phys. B At. Mol. Opt. Phys. 44 (2011) 193001, Eq. 3.19
@@ -129,29 +537,14 @@ pub fn set_m_row(m_mat: &mut DMatrix<f64>, spin_idx: &SpinIdx, mu: f64, t: f64,
     let latt = &dip.latt;
 
     let latt_pos = LattPos::from(spin_idx);
-    let n = dip.occupation[(latt_pos.y, latt_pos.x)] as f64;
 
-    let x = latt_pos.x as isize;
-    let y = latt_pos.y as isize;
+    let row_val = t*get_m_coeff(latt_pos.x, latt_pos.y, mu, dip);
 
-    let particle_e = get_particle_e(latt_pos.x, latt_pos.y, mu, dip);
-    let hole_e = get_hole_e(latt_pos.x, latt_pos.y, mu, dip);
-
-    let row_val = if particle_e==0. || hole_e==0. {
-        f64::INFINITY
-    } else {
-        -t*((n + 1.)/particle_e + n/hole_e) 
-    };
-
-    for (x_n, y_n) in [(x, y-1), (x, y+1), (x-1, y), (x+1, y)] {
-        
-        // get periodic indices
-        let x_n_p = latt.get_idx_periodic(x_n);
-        let y_n_p = latt.get_idx_periodic(y_n);
+    for (x_n_p, y_n_p, bond) in dip.get_hoppings(latt_pos.x, latt_pos.y) {
 
         // get the spin index of the neighbor
-        let spin_idx_n = SpinIdx::from(LattPos::new(x_n_p, y_n_p, &latt)); 
-        m_mat[(spin_idx.idx, spin_idx_n.idx)] = row_val
+        let spin_idx_n = latt.get_spin_idx(x_n_p, y_n_p);
+        m_mat[(spin_idx.idx, spin_idx_n)] = row_val*bond
     }
 }
 
@@ -166,11 +559,11 @@ pub fn generate_mat_m(mu: f64, t: f64,
     let latt = &dip.latt;
 
     // diagonal elements are always 1.
-    let mut m_mat = DMatrix::from_diagonal_element(latt.system_size.pow(2),
-                                                   latt.system_size.pow(2), 1.);
+    let mut m_mat = DMatrix::from_diagonal_element(latt.no_sites(),
+                                                   latt.no_sites(), 1.);
 
-    for spin_idx in 0..latt.system_size.pow(2) {
-        set_m_row(&mut m_mat, &SpinIdx::new(spin_idx, latt), mu, t, dip);
+    for spin_idx in 0..latt.no_sites() {
+        set_m_row(&mut m_mat, &SpinIdx { idx: spin_idx, latt }, mu, t, dip);
     }
 
     m_mat
@@ -199,70 +592,899 @@ pub fn get_tunneling(mu: f64, dip: &DipolarSystem, max_tunneling: f64,
     0.
 }
 
+/// Result of a critical tunneling search
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TunnelingResult {
+    /// Critical tunneling found after `iterations` steps, where
+    /// `residual` is det(M) at `tunneling` for the bisection and the
+    /// residual norm of the Lanczos iteration for the sparse solver
+    Found { tunneling: f64, residual: f64, iterations: usize },
+    /// No critical tunneling below the maximum tunneling
+    NotFound,
+    /// The iteration has not converged within its maximum number of steps
+    NotConverged,
+}
+
+impl TunnelingResult {
+    /// Get the critical tunneling, if it was found
+    pub fn tunneling(&self) -> Option<f64> {
+        match self {
+            TunnelingResult::Found { tunneling, .. } => Some(*tunneling),
+            TunnelingResult::NotFound | TunnelingResult::NotConverged => None,
+        }
+    }
+}
+
+/// Largest number of bracketing steps of get_tunneling_bisect
+pub const MAX_BRACKET_STEPS: usize = 100_000;
+
+/// Largest number of bisection steps of get_tunneling_bisect
+pub const MAX_BISECT_STEPS: usize = 200;
+
+/// Find smallest tunneling where the det(M)=0 by bracketing
+/// the first zero of det(M) and refining it with bisection.
+///
+/// Inside the stability region M is similar to I - t*S with S the
+/// symmetric matrix of get_tunneling_eigen, which is positive definite
+/// below the critical tunneling. The search walks from 0 in steps of
+/// bracket_step until the Cholesky decomposition of I - t*S fails and
+/// then bisects the bracket until it is narrower than tolerance. Unlike
+/// the sign of det(M) this also finds zeros of even order, where a
+/// degenerate largest eigenvalue of S makes det(M) touch zero without
+/// changing sign.
+///
+/// Returns NotFound if mu lies outside the stability region of the
+/// occupation, bracket_step or tolerance is not positive, or if either
+/// loop exceeds MAX_BRACKET_STEPS or MAX_BISECT_STEPS steps, e.g. for a
+/// tolerance below the rounding of the bracket.
+///
+/// # Parameters:
+/// * mu - chemical potential
+/// * max_tunneling - upper end of the search
+/// * bracket_step - step used to bracket the first zero
+/// * tolerance - width of the final bracket
+pub fn get_tunneling_bisect(mu: f64, dip: &DipolarSystem, max_tunneling: f64,
+                            bracket_step: f64, tolerance: f64) -> TunnelingResult {
+
+    if !(bracket_step > 0. && tolerance > 0.) {
+        return TunnelingResult::NotFound
+    }
+    let Some(s_mat) = generate_mat_s(mu, dip) else {
+        return TunnelingResult::NotFound
+    };
+
+    let identity = DMatrix::identity(s_mat.nrows(), s_mat.ncols());
+    let is_below = |t: f64| (&identity - &s_mat*t).cholesky().is_some();
+
+    // bracket the first zero
+    let mut lower = 0.;
+    let mut upper = bracket_step;
+    let mut steps = 0;
+
+    while is_below(upper) {
+        if upper >= max_tunneling || steps >= MAX_BRACKET_STEPS {
+            return TunnelingResult::NotFound
+        }
+        steps += 1;
+        lower = upper;
+        upper = (upper + bracket_step).min(max_tunneling);
+    }
+
+    // refine with bisection
+    let mut iterations = 0;
+
+    while upper - lower > tolerance {
+        if iterations >= MAX_BISECT_STEPS {
+            return TunnelingResult::NotFound
+        }
+        let mid = 0.5*(lower + upper);
+
+        if is_below(mid) {
+            lower = mid;
+        } else {
+            upper = mid;
+        }
+        iterations += 1;
+    }
+
+    let tunneling = 0.5*(lower + upper);
+
+    TunnelingResult::Found { tunneling, residual: generate_mat_m(mu, tunneling, dip).determinant(), iterations }
+}
+
+/// Get get_m_coeff of all sites indexed by spin index
+///
+/// Returns None if mu lies outside the stability region of the
+/// occupation, where some coefficients are not finite and negative.
+pub fn get_stable_m_coeffs(mu: f64, dip: &DipolarSystem) -> Option<DVector<f64>> {
+    let latt = &dip.latt;
+
+    let coeffs = DVector::from_fn(latt.no_sites(), |idx, _| {
+        let (x, y) = latt.get_spin_pos(idx);
+        get_m_coeff(x, y, mu, dip)
+    });
+
+    if coeffs.iter().any(|c| !c.is_finite() || *c >= 0.) {
+        None
+    } else {
+        Some(coeffs)
+    }
+}
+
+/// Generate the symmetrised hopping matrix S = |D|^(1/2) K |D|^(1/2),
+/// see get_tunneling_eigen
+///
+/// Returns None if mu lies outside the stability region of the occupation.
+pub fn generate_mat_s(mu: f64, dip: &DipolarSystem) -> Option<DMatrix<f64>> {
+    let latt = &dip.latt;
+    let no_sites = latt.no_sites();
+
+    let coeffs = get_stable_m_coeffs(mu, dip)?;
+
+    let mut s_mat = DMatrix::zeros(no_sites, no_sites);
+
+    for idx in 0..no_sites {
+        let (x, y) = latt.get_spin_pos(idx);
+
+        for (x_n, y_n, bond) in dip.get_hoppings(x, y) {
+            let idx_n = latt.get_spin_idx(x_n, y_n);
+            s_mat[(idx, idx_n)] = (coeffs[idx]*coeffs[idx_n]).sqrt()*bond;
+        }
+    }
+
+    Some(s_mat)
+}
+
+/// Find smallest tunneling where det(M)=0 from the eigenvalues of M.
+///
+/// M = I + t*A with A = D*K, where D is the diagonal of get_m_coeff
+/// and K the neighbour matrix. Inside the stability region all
+/// coefficients are negative, so A is similar to -S with
+/// S = |D|^(1/2) K |D|^(1/2) symmetric. The lowest eigenvalue of M then
+/// crosses zero at t = -1/lambda_min(A) = 1/lambda_max(S), without
+/// evaluating any determinant.
+///
+/// Returns None if mu lies outside the stability region of the
+/// occupation or no positive critical tunneling exists.
+pub fn get_tunneling_eigen(mu: f64, dip: &DipolarSystem) -> Option<f64> {
+    let lambda_max = generate_mat_s(mu, dip)?.symmetric_eigenvalues().max();
+
+    if lambda_max > 0. {
+        Some(1./lambda_max)
+    } else {
+        None
+    }
+}
+
+/// Get an upper bound of the critical tunneling
+///
+/// By Cauchy interlacing the largest eigenvalue of S, see
+/// get_tunneling_eigen, is at least |S_ij| of every bond, so the
+/// critical tunneling is at most 1/max |S_ij|. The bound follows the
+/// hopping amplitudes and the bond tunnelings of dip.
+///
+/// Returns None if mu lies outside the stability region of the
+/// occupation or the system has no bond.
+pub fn get_max_tunneling(mu: f64, dip: &DipolarSystem) -> Option<f64> {
+    let latt = &dip.latt;
+    let coeffs = get_stable_m_coeffs(mu, dip)?;
+
+    let s_max = (0..latt.no_sites())
+        .flat_map(|idx| {
+            let (x, y) = latt.get_spin_pos(idx);
+            let coeffs = &coeffs;
+
+            dip.get_hoppings(x, y).into_iter()
+                .map(move |(x_n, y_n, bond)| (coeffs[idx]*coeffs[latt.get_spin_idx(x_n, y_n)]).sqrt()*bond.abs())
+        })
+        .fold(0., f64::max);
+
+    if s_max > 0. {
+        Some(1./s_max)
+    } else {
+        None
+    }
+}
+
+/// Critical tunneling solver used in a sweep
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Solver {
+    /// Linear scan of det(M) against a threshold, see get_tunneling
+    Scan,
+    /// Bracketing and bisection of det(M) with the given bracket step
+    /// and tolerance, see get_tunneling_bisect
+    Bisection { bracket_step: f64, tolerance: f64 },
+    /// Largest eigenvalue of the symmetrised hopping matrix, see get_tunneling_eigen
+    Eigen,
+    /// Momentum space blocks of a periodic occupation, see get_tunneling_momentum
+    Momentum,
+    /// Lanczos iteration on the sparse hopping matrix with the given
+    /// relative residual tolerance and maximum number of steps,
+    /// see get_tunneling_sparse
+    Sparse { tolerance: f64, max_iter: usize },
+}
+
+/// Get the critical tunneling for a given mu using the selected solver
+///
+/// The scan and the bisection search up to twice get_max_tunneling, so
+/// that they also find a critical tunneling at the bound. The momentum
+/// solver falls back to the eigen solver for systems it does not
+/// support, see get_tunneling_momentum.
+/// Returns 0 if the critical tunneling is not found, and logs an
+/// error if the sparse solver has not converged.
+pub fn solve_tunneling(mu: f64, dip: &DipolarSystem, solver: &Solver) -> f64 {
+    match *solver {
+        Solver::Scan => get_max_tunneling(mu, dip)
+            .map(|max_tunneling| get_tunneling(mu, dip, 2.*max_tunneling, 1e-3, 1e-2))
+            .unwrap_or(0.),
+        Solver::Bisection { bracket_step, tolerance } => get_max_tunneling(mu, dip)
+            .and_then(|max_tunneling| get_tunneling_bisect(mu, dip, 2.*max_tunneling, bracket_step, tolerance)
+                      .tunneling())
+            .unwrap_or(0.),
+        Solver::Eigen => get_tunneling_eigen(mu, dip).unwrap_or(0.),
+        Solver::Momentum => match get_tunneling_momentum(mu, dip, None) {
+            Ok(tunneling) => tunneling.unwrap_or(0.),
+            // the unit cell found from dip is always valid, so the
+            // boundary or the bond tunneling breaks the translation symmetry
+            Err(_) => get_tunneling_eigen(mu, dip).unwrap_or(0.),
+        },
+        Solver::Sparse { tolerance, max_iter } => match get_tunneling_sparse(mu, dip, tolerance, max_iter) {
+            TunnelingResult::NotConverged => {
+                eprintln!("Sparse solver not converged after {} steps at mu {:.4}", max_iter, mu);
+                0.
+            },
+            result => result.tunneling().unwrap_or(0.),
+        },
+    }
+}
+
 /// Get the range of mu values in which the 
 /// occupation is stable with the parameters
-/// given in dip
+/// given in dip, including the site-dependent potential
 pub fn get_mu_inequality(dip: &DipolarSystem) -> (f64, f64) {
 
     let n_float = dip.occupation.clone().cast::<f64>();
-    let lower = dip.u_onsite*(n_float.add_scalar(-1.)) + &dip.dd_mat;
-    let upper = dip.u_onsite*n_float + &dip.dd_mat;
+    let lower = dip.u_onsite*(n_float.add_scalar(-1.)) + &dip.dd_mat + &dip.potential;
+    let upper = dip.u_onsite*n_float + &dip.dd_mat + &dip.potential;
 
     (lower.max(), upper.min())
 }
 
+/// Occupation pattern of a sweep
+///
+/// The fillings (n_a, n_b) are the occupations of the sites which are
+/// occupied and empty in the 0/1 pattern, see fill_pattern.
 #[non_exhaustive]
 #[derive(Debug)]
 pub enum Pattern {
-    Filled,
-    CB,
-    HStripe,
+    Filled(u8),
+    CB(u8, u8),
+    HStripe(u8, u8),
+    /// lowest energy occupation at t=0 and the given mu with
+    /// occupations up to n_max, see find_ground_state
+    Search { mu: f64, n_max: u8, seed: u64 },
+}
+
+/// Site-dependent potential of a sweep
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Potential {
+    Uniform,
+    /// harmonic trap, see get_harmonic_potential
+    Harmonic { strength: f64, centre: Option<(f64, f64)> },
+    /// potential of every site, which fixes the lattice size
+    Map(DMatrix<f64>),
+}
+
+impl Potential {
+    /// Get the potential of every site of the lattice
+    pub fn get_potential(&self, latt: &PeriodicLattice) -> DMatrix<f64> {
+        match self {
+            Potential::Uniform => DMatrix::zeros(latt.size_y, latt.ncols()),
+            Potential::Harmonic { strength, centre } => get_harmonic_potential(latt, *strength, *centre),
+            Potential::Map(potential) => potential.clone(),
+        }
+    }
+
+    /// Whether the potential is the same on every site
+    pub fn is_uniform(&self) -> bool {
+        match self {
+            Potential::Uniform => true,
+            Potential::Harmonic { strength, .. } => *strength == 0.,
+            Potential::Map(potential) => potential.iter().all(|&v| v == potential[(0, 0)]),
+        }
+    }
+}
+
+/// Parameters of a simulation sweep, see simulation_sweep
+///
+/// The optional stages are skipped if they are None or false.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct SweepParams {
+    pub pattern: Pattern,
+    /// interaction ranges int_ranges.0..int_ranges.1
+    pub int_ranges: (usize, usize),
+    /// every second system size of system_sizes.0..system_sizes.1
+    pub system_sizes: (usize, usize),
+    /// sizes along y, see system_sizes, square lattices if not given
+    pub system_sizes_y: Option<(usize, usize)>,
+    pub geometry: Geometry,
+    pub boundary: Boundary,
+    pub stacking: Stacking,
+    /// every combination of thetas and phis is run
+    pub thetas: DVector<f64>,
+    pub phis: DVector<f64>,
+    pub u_onsite: f64,
+    pub solver: Solver,
+    /// number of threads, 0 uses all available cores
+    pub num_threads: usize,
+    pub hopping: Hopping,
+    /// see DipolarSystem::bond_charge
+    pub bond_charge: f64,
+    pub potential: Potential,
+    /// average the lobes over disorder realisations
+    pub disorder: Option<Disorder>,
+    /// use the complete periodic lattice sum of the d-d interaction
+    pub lattice_sum: bool,
+    pub phase_map: Option<PhaseMapGrid>,
+    pub enumeration: Option<EnumerationParams>,
+    /// number of successive lobes, see get_next_lobe
+    pub no_lobes: u8,
+    pub diagram: Option<DiagramParams>,
+    /// extrapolate the lobe tips in 1/L^power, see fit_finite_size
+    pub extrapolation_power: Option<u32>,
+}
+
+impl SweepParams {
+    /// Create the parameters of a sweep of the first lobe of pattern
+    /// on periodic square lattices of a single layer with the dipoles
+    /// along z and the scan solver, without any optional stage
+    pub fn new(pattern: Pattern, int_ranges: (usize, usize), system_sizes: (usize, usize),
+               u_onsite: f64) -> Self {
+        SweepParams { pattern, int_ranges, system_sizes, system_sizes_y: None,
+                      geometry: Geometry::Square, boundary: Boundary::Periodic, stacking: Stacking::Open(1, 1.),
+                      thetas: DVector::zeros(1), phis: DVector::zeros(1), u_onsite,
+                      solver: Solver::Scan, num_threads: 1, hopping: Hopping::default(), bond_charge: 0.,
+                      potential: Potential::Uniform, disorder: None, lattice_sum: false, phase_map: None,
+                      enumeration: None, no_lobes: 1, diagram: None, extrapolation_power: None }
+    }
+}
+
+/// Labels of the output files of one system size, interaction
+/// range and dipole orientation of a sweep
+struct SweepLabels<'a> {
+    save_path: &'a Path,
+    size: String,
+    range: String,
+    angle: String,
+}
+
+impl SweepLabels<'_> {
+    /// Get the path of the file {name}_{size}_range_{range}{angle}{lobe}.json
+    fn get_path(&self, name: &str, lobe: &str) -> PathBuf {
+        self.save_path.join(format!("{name}_{}_range_{}{}{lobe}.json", self.size, self.range, self.angle))
+    }
+}
+
+/// Label of a lobe in the output files, empty for a single lobe
+fn get_lobe_label(lobe: usize, no_lobes: u8) -> String {
+    if no_lobes > 1 {
+        format!("_lobe_{lobe}")
+    } else {
+        String::new()
+    }
+}
+
+/// Rows of an occupation, as saved in the output files
+fn get_occupation_rows(occupation: &DMatrix<u8>) -> Vec<Vec<u8>> {
+    occupation.row_iter()
+        .map(|row| row.iter().copied().collect())
+        .collect()
+}
+
+/// Check the combinations of sweep parameters the solvers do not support
+fn check_sweep_params(params: &SweepParams) -> Result<()> {
+    // bond disorder and open boundaries break the translation symmetry of the momentum solver
+    if matches!(params.solver, Solver::Momentum) && params.disorder.as_ref().is_some_and(|disorder| disorder.tunneling != 0.) {
+        return Err(Error::Config("the momentum solver does not support bond disorder".to_string()))
+    }
+    if matches!(params.solver, Solver::Momentum) && params.boundary != Boundary::Periodic {
+        return Err(Error::Config("the momentum solver requires periodic boundaries".to_string()))
+    }
+    if params.lattice_sum && !params.boundary.is_periodic() {
+        return Err(Error::Config("the lattice sum requires periodic boundaries".to_string()))
+    }
+    // the enumeration needs the translation symmetry of the lattice
+    if params.enumeration.is_some() && !params.boundary.is_periodic() {
+        return Err(Error::Config("the enumeration requires periodic boundaries".to_string()))
+    }
+    if params.enumeration.is_some() && !params.potential.is_uniform() {
+        return Err(Error::Config("the enumeration requires a uniform potential".to_string()))
+    }
+    Ok(())
+}
+
+/// Get the (size_x, size_y) lattice sizes of a sweep
+///
+/// A potential map fixes the lattice size, so the sizes which do not fit
+/// it are skipped. Returns an error if none fits or a lattice cannot be
+/// created.
+fn get_sweep_sizes(params: &SweepParams) -> Result<Vec<(usize, usize)>> {
+    let (start, end) = params.system_sizes;
+
+    // square lattices, unless the y sizes are swept separately
+    let sizes: Vec<(usize, usize)> = match params.system_sizes_y {
+        None => (start..end).step_by(2)
+            .map(|size| (size, size))
+            .collect(),
+        Some(sizes_y) => (start..end).step_by(2)
+            .flat_map(|size_x| (sizes_y.0..sizes_y.1).step_by(2).map(move |size_y| (size_x, size_y)))
+            .collect(),
+    };
+
+    let Potential::Map(map) = &params.potential else { return Ok(sizes) };
+
+    let mut fitting = Vec::new();
+    for (size_x, size_y) in sizes {
+        let ncols = PeriodicLattice::new_geometry(params.geometry, size_x, size_y)?
+            .with_stacking(params.stacking)?
+            .ncols();
+        if map.shape() == (size_y, ncols) {
+            fitting.push((size_x, size_y));
+        } else {
+            println!("Skipping system size {size_x}x{size_y}, which does not fit the {}x{} potential map",
+                     map.nrows(), map.ncols());
+        }
+    }
+
+    if fitting.is_empty() {
+        return Err(Error::Config(format!("no system size fits the {}x{} potential map",
+                                         map.nrows(), map.ncols())))
+    }
+    Ok(fitting)
+}
+
+/// Get the occupation of the sweep pattern on the lattice of dip
+///
+/// The occupation found by the "search" pattern is saved.
+fn get_sweep_occupation(pattern: &Pattern, dip: &DipolarSystem, labels: &SweepLabels) -> Result<DMatrix<u8>> {
+    let occupation = match *pattern {
+        Pattern::Filled(n) => get_filled(&dip.latt)*n,
+        Pattern::CB(n_a, n_b) => fill_pattern(&get_checkerboard(&dip.latt), (n_a, n_b)),
+        Pattern::HStripe(n_a, n_b) => fill_pattern(&get_horizontal_stripe(&dip.latt), (n_a, n_b)),
+        Pattern::Search { mu, n_max, seed } => {
+            let result = find_ground_state(mu, dip, n_max, &AnnealingSchedule::default(), seed, 4);
+            println!("Found {} pattern with energy {:.4} per site",
+                     classify_density(&result.occupation.map(|n| n as f64), &dip.latt, 1e-6).label(),
+                     result.energy);
+
+            util::save_json(labels.get_path("occupation", ""), &get_occupation_rows(&result.occupation))?;
+
+            result.occupation
+        },
+    };
+    Ok(occupation)
+}
+
+/// Get the d-d kernel of a sweep, the complete lattice sum with
+/// lattice_sum, or None on lattices which are not periodic
+///
+/// The kernel only depends on the orientation, the interaction
+/// range and the lattice, so it is shared by all lobes.
+fn get_sweep_kernel(dip: &DipolarSystem, lattice_sum: bool) -> Result<Option<DdKernel>> {
+    if !dip.latt.boundary.is_periodic() {
+        Ok(None)
+    } else if lattice_sum {
+        Ok(Some(DdKernel::new(get_dd_kernel_lattice_sum(dip, 1e-8), &dip.latt)?))
+    } else {
+        Ok(Some(get_dd_kernel(dip)?))
+    }
+}
+
+/// Generate the d-d interaction matrix of a sweep with the kernel
+/// from get_sweep_kernel, by direct summation if there is none
+///
+/// With the kernel truncated at int_range the truncation error of the
+/// interaction is printed, see get_truncation_error.
+fn generate_sweep_dd_int_mat(dip: &mut DipolarSystem, kernel: Option<&DdKernel>,
+                             truncated: Option<&DdKernel>) -> Result<()> {
+    match kernel {
+        Some(kernel) => {
+            generate_dd_int_mat_fft(dip, kernel)?;
+            if let Some(truncated) = truncated {
+                println!("Truncation error of int range {}: {:.2e}", dip.int_range,
+                         (&dip.dd_mat - convolve_dd_kernel(dip, truncated)?).amax());
+            }
+            Ok(())
+        },
+        None => {
+            generate_dd_int_mat(dip);
+            Ok(())
+        },
+    }
 }
 
-pub fn simulation_sweep<P: AsRef<Path>>(save_path: P, patt: &Pattern,
-                                        int_ranges: (usize, usize), 
-                                        system_sizes: (usize, usize), theta: f64, phi: f64,
-                                        u_onsite: f64) {
+/// Average the critical tunneling of the lobe at mu_vals over the
+/// disorder realisations and save all realisations with their mean
+/// and standard deviation
+///
+/// A realisation has 0 where its occupation is not stable.
+fn run_disorder(params: &SweepParams, disorder: &Disorder, dip: &DipolarSystem, mu_vals: &DVector<f64>,
+                labels: &SweepLabels, lobe_label: &str) -> Result<()> {
+    let seeds = disorder.seeds();
+
+    let realisations: Vec<Vec<f64>> = seeds.iter()
+        .map(|&seed| {
+            let mut disordered = dip.clone();
+            apply_disorder(&mut disordered, disorder, seed);
+            let (lower, upper) = get_mu_inequality(&disordered);
+
+            util::parallel_map(mu_vals, params.num_threads, |mu| {
+                if lower < mu && mu < upper {
+                    solve_tunneling(mu, &disordered, &params.solver)
+                } else {
+                    0.
+                }
+            }).as_slice().to_vec()
+        })
+        .collect();
+
+    let no_points = mu_vals.len();
+    let no_realisations = realisations.len().max(1) as f64;
+    let mean: Vec<f64> = (0..no_points)
+        .map(|point| realisations.iter().map(|t| t[point]).sum::<f64>()/no_realisations)
+        .collect();
+    let std: Vec<f64> = (0..no_points)
+        .map(|point| (realisations.iter().map(|t| (t[point] - mean[point]).powi(2)).sum::<f64>()
+                      /no_realisations).sqrt())
+        .collect();
+
+    util::save_json(labels.get_path("disorder", lobe_label),
+                    &serde_json::json!({
+                        "onsite": disorder.onsite,
+                        "tunneling_width": disorder.tunneling,
+                        "seeds": seeds,
+                        "mu": mu_vals.as_slice(),
+                        "tunneling": realisations,
+                        "tunneling_mean": mean,
+                        "tunneling_std": std,
+                    }))
+}
+
+/// Solve the critical tunneling of the successive lobes of occupation,
+/// see get_next_lobe, and save the lobes
+///
+/// Returns the tip of every lobe, None where the lobe is not stable
+/// or has no positive critical tunneling.
+fn run_lobes(params: &SweepParams, dip: &mut DipolarSystem, occupation: &DMatrix<u8>, lattice_sum: bool,
+             labels: &SweepLabels) -> Result<Vec<Option<LobeTip>>> {
+    let kernel = get_sweep_kernel(dip, lattice_sum)?;
+    // kernel truncated at int_range for the truncation error of the lattice sum
+    let truncated = if lattice_sum { Some(get_dd_kernel(dip)?) } else { None };
+
+    let mut lobes = vec![occupation.clone()];
+    for _ in 1..params.no_lobes.max(1) {
+        lobes.push(get_next_lobe(lobes.last().unwrap(), occupation)?);
+    }
+
+    let mut tips = Vec::with_capacity(lobes.len());
+
+    for (lobe, lobe_occupation) in lobes.into_iter().enumerate() {
+        let lobe_label = get_lobe_label(lobe, params.no_lobes);
+
+        dip.update_occupation(lobe_occupation)?;
+        generate_sweep_dd_int_mat(dip, kernel.as_ref(), truncated.as_ref())?;
+        let (lower, upper) = get_mu_inequality(dip);
+
+        println!("Lobe {} lower mu {:.2} upper mu {:.2}", lobe, lower, upper);
+
+        if lower >= upper {
+            tips.push(None);
+            continue
+        }
+
+        let mu_vals = util::linspace(lower, upper, 100, true);
+        let tunneling = util::parallel_map(&mu_vals, params.num_threads,
+                                           |mu| solve_tunneling(mu, dip, &params.solver));
+
+        tips.push(get_lobe_tip(&mu_vals, &tunneling));
+
+        if let Some(disorder) = &params.disorder {
+            run_disorder(params, disorder, dip, &mu_vals, labels, &lobe_label)?;
+        }
+
+        util::save_vector_json(labels.get_path("tunneling", &lobe_label), tunneling)?;
+        util::save_vector_json(labels.get_path("mu", &lobe_label), mu_vals)?;
+    }
+
+    Ok(tips)
+}
+
+/// Enumerate the periodic patterns of the lattice of dip and save their
+/// atomic limit ground state mu ranges
+///
+/// Returns the occupations of the ground states.
+fn run_enumeration(params: &EnumerationParams, dip: &DipolarSystem, labels: &SweepLabels) -> Result<Vec<DMatrix<u8>>> {
+    println!("Enumerating patterns");
+
+    let patterns = enumerate_patterns(dip, params.max_cell_sites, params.n_max)?;
+    let mut ground_states = Vec::new();
+
+    let ranges: Vec<serde_json::Value> = get_ground_state_ranges(&patterns, params.mu_range)
+        .into_iter()
+        .map(|(idx, mu_range)| {
+            let pattern = &patterns[idx];
+            let order = classify_density(&pattern.occupation.map(|n| n as f64), &dip.latt, 1e-6);
+            ground_states.push(pattern.occupation.clone());
+
+            println!("mu {:.3} to {:.3}: {} pattern with density {:.3}, stable from {:.3} to {:.3}",
+                     mu_range.0, mu_range.1, order.label(), pattern.density,
+                     pattern.stable_range.0, pattern.stable_range.1);
+
+            serde_json::json!({
+                "mu_range": mu_range,
+                "stable_range": pattern.stable_range,
+                "density": pattern.density,
+                "unit_cell": pattern.unit_cell,
+                "shear": pattern.shear,
+                "order": order.label(),
+                "occupation": get_occupation_rows(&pattern.occupation),
+            })
+        })
+        .collect();
+
+    util::save_json(labels.get_path("atomic_phases", ""), &ranges)?;
+    Ok(ground_states)
+}
+
+/// Assemble and save the phase diagram of the candidate patterns
+/// together with the atomic limit ground states of the enumeration
+fn run_phase_diagram(params: &SweepParams, diagram_params: &DiagramParams, dip: &DipolarSystem,
+                     atomic_ground_states: Vec<DMatrix<u8>>, lattice_sum: bool,
+                     labels: &SweepLabels) -> Result<()> {
+    println!("Running phase diagram");
+
+    let mut candidates = get_candidate_patterns(&dip.latt, diagram_params.n_max);
+    if dip.latt.boundary.is_periodic() {
+        let mut seen: Vec<Vec<u8>> = candidates.iter()
+            .map(|candidate| get_canonical_occupation(&candidate.occupation, &dip.latt))
+            .collect();
+
+        for (idx, occupation) in atomic_ground_states.into_iter().enumerate() {
+            let canonical = get_canonical_occupation(&occupation, &dip.latt);
+            if !seen.contains(&canonical) {
+                seen.push(canonical);
+                candidates.push(Candidate::new(format!("enumerated_{idx}"), occupation));
+            }
+        }
+    }
+
+    let diagram = get_phase_diagram(&candidates, dip, diagram_params, &params.solver,
+                                    lattice_sum, params.num_threads)?;
+
+    let patterns: Vec<serde_json::Value> = candidates.iter()
+        .map(|candidate| serde_json::json!({ "label": candidate.label,
+                                              "occupation": get_occupation_rows(&candidate.occupation) }))
+        .collect();
+    let lobes: Vec<serde_json::Value> = diagram.lobes.iter()
+        .map(|lobe| serde_json::json!({
+            "pattern": lobe.pattern,
+            "stable_range": lobe.stable_range,
+            "mu": lobe.mu.as_slice(),
+            "tunneling": lobe.tunneling.as_slice(),
+        }))
+        .collect();
+
+    util::save_json(labels.get_path("phase_diagram", ""),
+                    &serde_json::json!({
+                        "patterns": patterns,
+                        "lobes": lobes,
+                        "mu": diagram.mu.as_slice(),
+                        "tunneling": diagram.tunneling.as_slice(),
+                        "pattern": diagram.pattern,
+                        "max_tunneling": diagram.max_tunneling.as_slice(),
+                        "max_pattern": diagram.max_pattern,
+                    }))
+}
+
+/// Compute and save the Gutzwiller phase map of dip on the grid
+fn run_phase_map(grid: &PhaseMapGrid, dip: &DipolarSystem, num_threads: usize, labels: &SweepLabels) -> Result<()> {
+    println!("Running phase map");
+
+    let mu_vals = util::linspace(grid.mu_range.0, grid.mu_range.1, grid.no_points, true);
+    let t_vals = util::linspace(0., grid.t_max, grid.no_points, true);
+    let phases = get_phase_map(&mu_vals, &t_vals, dip, grid.n_max, num_threads);
+
+    // rows over t, columns over mu
+    let labels_map: Vec<Vec<String>> = phases.row_iter()
+        .map(|row| row.iter().map(|phase| phase.label()).collect())
+        .collect();
+
+    util::save_json(labels.get_path("phases", ""), &labels_map)?;
+    util::save_vector_json(labels.get_path("phase_mu", ""), mu_vals)?;
+    util::save_vector_json(labels.get_path("phase_t", ""), t_vals)
+}
+
+/// Save the lobe tips of every dipole orientation at one system size,
+/// indexed by lobe and orientation
+fn save_angle_tips(save_path: &Path, angles: &[(f64, f64)], angle_tips: &[Vec<Option<LobeTip>>],
+                   size_label: &str, range_label: &str, no_lobes: u8) -> Result<()> {
+    for (lobe, lobe_tips) in angle_tips.iter().enumerate() {
+        let lobe_label = get_lobe_label(lobe, no_lobes);
+
+        util::save_json(save_path.join(format!("lobe_tips_angle_{size_label}_range_{range_label}{lobe_label}.json")),
+                        &serde_json::json!({
+                            "theta": angles.iter().map(|(theta, _)| theta/PI).collect::<Vec<f64>>(),
+                            "phi": angles.iter().map(|(_, phi)| phi/PI).collect::<Vec<f64>>(),
+                            "mu": lobe_tips.iter().map(|tip| tip.map(|tip| tip.mu)).collect::<Vec<_>>(),
+                            "tunneling": lobe_tips.iter().map(|tip| tip.map(|tip| tip.tunneling))
+                                .collect::<Vec<_>>(),
+                        }))?;
+    }
+    Ok(())
+}
+
+/// Extrapolate the lobe tips of every orientation and lobe over the
+/// system sizes and save the fits
+///
+/// tips holds the (linear size, tip) pairs indexed by orientation and lobe.
+fn save_extrapolation(save_path: &Path, power: u32, tips: &[Vec<Vec<(f64, LobeTip)>>], angle_labels: &[String],
+                      range_label: &str, no_lobes: u8) -> Result<()> {
+    for (orientation_tips, angle_label) in tips.iter().zip(angle_labels) {
+        for (lobe, lobe_tips) in orientation_tips.iter().enumerate() {
+            let lobe_label = get_lobe_label(lobe, no_lobes);
+
+            let lengths: Vec<f64> = lobe_tips.iter().map(|(length, _)| *length).collect();
+            let tip_mu: Vec<f64> = lobe_tips.iter().map(|(_, tip)| tip.mu).collect();
+            let tip_tunneling: Vec<f64> = lobe_tips.iter().map(|(_, tip)| tip.tunneling).collect();
+
+            let Some(fit_tunneling) = fit_finite_size(&lengths, &tip_tunneling, power) else {
+                println!("Lobe {}{} has tips at fewer than two sizes, not extrapolated", lobe, angle_label);
+                continue
+            };
+            let fit_mu = fit_finite_size(&lengths, &tip_mu, power).unwrap();
+
+            println!("Lobe {}{} tip extrapolated to t {:.5} +- {:.1e} at mu {:.4} +- {:.1e}", lobe, angle_label,
+                     fit_tunneling.intercept, fit_tunneling.intercept_err.unwrap_or(f64::NAN),
+                     fit_mu.intercept, fit_mu.intercept_err.unwrap_or(f64::NAN));
+
+            let fit_json = |fit: &FiniteSizeFit| serde_json::json!({
+                "intercept": fit.intercept,
+                "intercept_err": fit.intercept_err,
+                "slope": fit.slope,
+                "slope_err": fit.slope_err,
+            });
+            util::save_json(save_path.join(format!("lobe_tips_range_{range_label}{angle_label}{lobe_label}.json")),
+                            &serde_json::json!({
+                                "power": power,
+                                "length": lengths,
+                                "mu": tip_mu,
+                                "tunneling": tip_tunneling,
+                                "fit_mu": fit_json(&fit_mu),
+                                "fit_tunneling": fit_json(&fit_tunneling),
+                            }))?;
+        }
+    }
+    Ok(())
+}
+
+/// Run the sweep over interaction ranges, system sizes and dipole
+/// orientations and save the results as JSON files in save_path
+///
+/// Returns an error if the solvers do not support the parameters or
+/// a result cannot be saved.
+pub fn simulation_sweep<P: AsRef<Path>>(save_path: P, params: &SweepParams) -> Result<()> {
     let start = Instant::now();
+    let save_path = save_path.as_ref();
 
-    for int_range in (int_ranges.0..int_ranges.1).step_by(1) {
-        for system_size in (system_sizes.0..system_sizes.1).step_by(2) {
-            println!("Running int range {}, system size {}", int_range, system_size);
+    check_sweep_params(params)?;
+    fs::create_dir_all(save_path)?;
 
-            let mut dip_system = DipolarSystem::new(theta, phi, u_onsite, int_range, system_size);
+    let sizes = get_sweep_sizes(params)?;
 
-            let occupation = match patt {
-                Pattern::Filled => get_filled(&dip_system.latt),
-                Pattern::CB => get_checkerboard(&dip_system.latt),
-                Pattern::HStripe => get_horizontal_stripe(&dip_system.latt),
+    let lattice_sum = params.lattice_sum;
+
+    // the complete lattice sum does not depend on the interaction range,
+    // only the first range is run to report its truncation error
+    let int_ranges = if lattice_sum {
+        (params.int_ranges.0, params.int_ranges.0 + 1)
+    } else {
+        params.int_ranges
+    };
+
+    // every combination of theta and phi, labelled in fractions of PI
+    let angles: Vec<(f64, f64)> = params.thetas.iter()
+        .flat_map(|&theta| params.phis.iter().map(move |&phi| (theta, phi)))
+        .collect();
+    let angle_labels: Vec<String> = angles.iter()
+        .map(|(theta, phi)| if angles.len() > 1 {
+            format!("_theta_{:.4}_phi_{:.4}", theta/PI, phi/PI)
+        } else {
+            String::new()
+        })
+        .collect();
+    let no_lobes = params.no_lobes.max(1) as usize;
+
+    for int_range in int_ranges.0..int_ranges.1 {
+        let range_label = if lattice_sum {
+            "full".to_string()
+        } else {
+            int_range.to_string()
+        };
+
+        // lobe tips of every size, indexed by orientation and lobe
+        let mut tips: Vec<Vec<Vec<(f64, LobeTip)>>> = vec![vec![Vec::new(); no_lobes]; angles.len()];
+
+        for &(size_x, size_y) in &sizes {
+            let size_label = if size_x == size_y {
+                size_x.to_string()
+            } else {
+                format!("{size_x}x{size_y}")
             };
 
-            dip_system.update_occupation(occupation);
+            println!("Running int range {}, system size {}", int_range, size_label);
+
+            let latt = PeriodicLattice::new_geometry(params.geometry, size_x, size_y)?
+                .with_boundary(params.boundary)?
+                .with_stacking(params.stacking)?;
+            // lobe tips of every orientation at this size, indexed by lobe
+            let mut angle_tips: Vec<Vec<Option<LobeTip>>> = vec![Vec::new(); no_lobes];
+
+            for (angle_idx, &(theta, phi)) in angles.iter().enumerate() {
+                if angles.len() > 1 {
+                    println!("Running theta {:.4} PI, phi {:.4} PI", theta/PI, phi/PI);
+                }
+                let labels = SweepLabels { save_path, size: size_label.clone(), range: range_label.clone(),
+                                           angle: angle_labels[angle_idx].clone() };
+
+                let mut dip_system = DipolarSystem::from_lattice(theta, phi, params.u_onsite, int_range, latt.clone());
+                dip_system.hopping = params.hopping;
+                dip_system.bond_charge = params.bond_charge;
+                dip_system.update_potential(params.potential.get_potential(&dip_system.latt))?;
 
-            generate_dd_int_mat(&mut dip_system);
-            let (lower, upper) = get_mu_inequality(&dip_system);
+                let occupation = get_sweep_occupation(&params.pattern, &dip_system, &labels)?;
 
-            println!("Lower mu {:.2} upper mu {:.2}", lower, upper);
+                let lobe_tips = run_lobes(params, &mut dip_system, &occupation, lattice_sum, &labels)?;
+                for (lobe, tip) in lobe_tips.into_iter().enumerate() {
+                    if let Some(tip) = tip {
+                        tips[angle_idx][lobe].push((((size_x*size_y) as f64).sqrt(), tip));
+                    }
+                    angle_tips[lobe].push(tip);
+                }
 
-                if lower < upper {
-                    let no_points = 100;
-                    let mu_vals = util::linspace(lower, upper, no_points, true);
+                // the phase map starts from the pattern of the first lobe
+                dip_system.update_occupation(occupation)?;
 
-                    let tunneling = DVector::from_iterator(no_points, 
-                                                        mu_vals.iter()
-                                                                .map(|mu| get_tunneling(*mu, &dip_system,
-                                                                                        4.,  1e-3, 1e-2)));
-    
-                    util::save_vector_json(save_path.as_ref()
-                                                    .join(format!("tunneling_{system_size}_range_{int_range}.json"))
-                                            , tunneling);
-                    util::save_vector_json(save_path.as_ref()
-                                                    .join(format!("mu_{system_size}_range_{int_range}.json"))
-                                           , mu_vals);
+                // atomic limit ground states of the enumeration, candidates of the phase diagram
+                let atomic_ground_states = match &params.enumeration {
+                    Some(enumeration) => run_enumeration(enumeration, &dip_system, &labels)?,
+                    None => Vec::new(),
+                };
+
+                if let Some(diagram) = &params.diagram {
+                    run_phase_diagram(params, diagram, &dip_system, atomic_ground_states, lattice_sum, &labels)?;
+                }
+
+                if let Some(grid) = &params.phase_map {
+                    run_phase_map(grid, &dip_system, params.num_threads, &labels)?;
+                }
+            }
+
+            if angles.len() > 1 {
+                save_angle_tips(save_path, &angles, &angle_tips, &size_label, &range_label, params.no_lobes)?;
             }
         }
+
+        if let Some(power) = params.extrapolation_power {
+            save_extrapolation(save_path, power, &tips, &angle_labels, &range_label, params.no_lobes)?;
+        }
+
         let duration = start.elapsed();
 
         println!("Simulation sweep took: {:.4} s", duration.as_secs_f64());
     }
-}
\ No newline at end of file
+
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use na::DMatrix;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+
+use crate::dipolar::DipolarSystem;
+use crate::lattice::PeriodicLattice;
+
+/// Quenched disorder of the onsite energies and the bond tunnelings
+///
+/// The onsite energies are uniform in [-onsite/2, onsite/2] and the
+/// relative bond tunnelings uniform in [1 - tunneling/2, 1 + tunneling/2].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Disorder {
+    /// width of the onsite energy distribution
+    pub onsite: f64,
+    /// width of the relative bond tunneling distribution
+    pub tunneling: f64,
+    /// number of disorder realisations of a sweep
+    pub no_realisations: usize,
+    /// realisation k uses the seed seed + k
+    pub seed: u64,
+}
+
+impl Disorder {
+    pub fn new(onsite: f64, tunneling: f64, no_realisations: usize, seed: u64) -> Self {
+        Disorder { onsite, tunneling, no_realisations, seed }
+    }
+
+    /// Seeds of all realisations
+    pub fn seeds(&self) -> Vec<u64> {
+        (0..self.no_realisations as u64).map(|realisation| self.seed + realisation).collect()
+    }
+}
+
+/// Get random onsite energies uniform in [-width/2, width/2]
+pub fn get_onsite_disorder(latt: &PeriodicLattice, width: f64, rng: &mut Pcg64) -> DMatrix<f64> {
+    DMatrix::from_fn(latt.size_y, latt.ncols(), |_, _| width*(rng.gen::<f64>() - 0.5))
+}
+
+/// Get random relative tunnelings uniform in [1 - width/2, 1 + width/2]
+/// of all bonds of dip, keyed as DipolarSystem::bond_tunneling
+///
+/// The bonds are those of DipolarSystem::get_hopping_offsets, i.e. the
+/// nearest neighbour, interlayer and next-nearest neighbour bonds with
+/// a nonzero hopping amplitude.
+pub fn get_bond_disorder(dip: &DipolarSystem, width: f64, rng: &mut Pcg64) -> HashMap<(usize, usize), f64> {
+    let latt = &dip.latt;
+    let mut bonds = HashMap::new();
+
+    // sites in spin index order, so that the bonds are drawn in a fixed order
+    for idx in 0..latt.no_sites() {
+        let (x, y) = latt.get_spin_pos(idx);
+
+        for (n1, n2, s_n, _) in dip.get_hopping_offsets(x%latt.n_cell_sites()) {
+            let Some((x_n, y_n)) = latt.get_offset_pos(x, y, n1, n2, s_n) else { continue };
+            let idx_n = latt.get_spin_idx(x_n, y_n);
+
+            if idx < idx_n {
+                bonds.entry((idx, idx_n)).or_insert_with(|| 1. + width*(rng.gen::<f64>() - 0.5));
+            }
+        }
+    }
+
+    bonds
+}
+
+/// Apply the disorder realisation with the given seed to dip
+///
+/// The onsite energies are added to the potential, and the bond
+/// tunnelings replace the ones of dip if the tunneling disorder is
+/// not zero. The same seed always gives the same realisation.
+pub fn apply_disorder(dip: &mut DipolarSystem, disorder: &Disorder, seed: u64) {
+    let mut rng = Pcg64::seed_from_u64(seed);
+
+    let onsite = get_onsite_disorder(&dip.latt, disorder.onsite, &mut rng);
+    // the onsite energies are drawn on the lattice of dip
+    dip.potential += onsite;
+
+    if disorder.tunneling != 0. {
+        dip.bond_tunneling = get_bond_disorder(dip, disorder.tunneling, &mut rng);
+    }
+}
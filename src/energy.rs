@@ -0,0 +1,91 @@
+use na::DMatrix;
+
+use crate::dipolar::DipolarSystem;
+
+/// Energy of an occupation in the atomic (t=0) limit, split into its terms
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct Energy {
+    /// sum of U n(n-1)/2
+    pub onsite: f64,
+    /// sum of the d-d pair energies
+    pub dipolar: f64,
+    /// sum of the site-dependent potential V n
+    pub potential: f64,
+    /// -mu N
+    pub chemical: f64,
+}
+
+impl Energy {
+    pub fn total(&self) -> f64 {
+        self.onsite + self.dipolar + self.potential + self.chemical
+    }
+}
+
+fn get_onsite_e(n: f64, dip: &DipolarSystem) -> f64 {
+    0.5*dip.u_onsite*n*(n - 1.)
+}
+
+/// Get the energy of the occupation of dip
+///
+/// Assumes that dd_mat belongs to the occupation,
+/// as get_particle_e and get_hole_e do.
+pub fn get_energy(mu: f64, dip: &DipolarSystem) -> Energy {
+    let mut energy = Energy { onsite: 0., dipolar: 0., potential: 0., chemical: 0. };
+
+    for ((&n, &dd), &v) in dip.occupation.iter().zip(dip.dd_mat.iter()).zip(dip.potential.iter()) {
+        let n = n as f64;
+
+        energy.onsite += get_onsite_e(n, dip);
+        // every pair is shared by two sites
+        energy.dipolar += 0.5*n*dd;
+        energy.potential += v*n;
+        energy.chemical -= mu*n;
+    }
+
+    energy
+}
+
+/// Get the energy of site (y, x), with half of the d-d pair energy,
+/// so that the site energies sum to the total energy
+pub fn get_site_energy(x: usize, y: usize, mu: f64, dip: &DipolarSystem) -> f64 {
+    let n = dip.occupation[(y, x)] as f64;
+
+    get_onsite_e(n, dip) + 0.5*n*dip.dd_mat[(y, x)] + (dip.potential[(y, x)] - mu)*n
+}
+
+/// Get the energies of all sites, see get_site_energy
+pub fn get_site_energies(mu: f64, dip: &DipolarSystem) -> DMatrix<f64> {
+    DMatrix::from_fn(dip.latt.size_y, dip.latt.ncols(), |y, x| get_site_energy(x, y, mu, dip))
+}
+
+/// Get the change of the energy if the occupation of site (y, x)
+/// is set to n_new
+///
+/// Adding or removing a single particle gives get_particle_e
+/// and get_hole_e.
+pub fn get_move_energy(x: usize, y: usize, n_new: u8, mu: f64, dip: &DipolarSystem) -> f64 {
+    let n = dip.occupation[(y, x)] as f64;
+    let n_new = n_new as f64;
+
+    get_onsite_e(n_new, dip) - get_onsite_e(n, dip) + (n_new - n)*(dip.dd_mat[(y, x)] + dip.potential[(y, x)] - mu)
+}
+
+/// Set the occupation of site (y, x) to n_new and update dd_mat
+/// with the d-d offsets from get_dd_offsets
+pub fn set_site_occupation(x: usize, y: usize, n_new: u8, dip: &mut DipolarSystem,
+                           dd_offsets: &[Vec<(isize, isize, usize, f64)>]) {
+    let latt = &dip.latt;
+    let delta = n_new as f64 - dip.occupation[(y, x)] as f64;
+
+    for &(n1, n2, s_n, interaction) in &dd_offsets[x%latt.n_cell_sites()] {
+        if let Some((x_n, y_n)) = latt.get_offset_pos(x, y, n1, n2, s_n) {
+            // skip the periodic images of the site itself
+            if (x_n, y_n) != (x, y) {
+                dip.dd_mat[(y_n, x_n)] += delta*interaction;
+            }
+        }
+    }
+
+    dip.occupation[(y, x)] = n_new;
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+
+/// Errors of the crate
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Error {
+    /// matrix whose shape does not match the lattice
+    Shape { name: &'static str, expected: (usize, usize), found: (usize, usize) },
+    /// lattice position (x, y) outside of the lattice
+    Position { x: usize, y: usize },
+    /// spin index outside of the lattice
+    SpinIdx { idx: usize, no_sites: usize },
+    /// unit cell (cx, cy) the system is not periodic with
+    UnitCell { unit_cell: (usize, usize) },
+    /// method which does not support the system, e.g. its boundary
+    Unsupported(&'static str),
+    /// invalid or incomplete config
+    Config(String),
+    /// reading or writing a file failed
+    Io(std::io::Error),
+    /// reading or writing JSON failed
+    Json(serde_json::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Shape { name, expected, found } =>
+                write!(f, "{name} has shape {found:?}, the lattice needs {expected:?}"),
+            Error::Position { x, y } =>
+                write!(f, "position (x {x}, y {y}) not compatible with the given system size"),
+            Error::SpinIdx { idx, no_sites } =>
+                write!(f, "index {idx} not compatible with the system size of {no_sites} sites"),
+            Error::UnitCell { unit_cell } =>
+                write!(f, "system is not periodic with the unit cell {unit_cell:?}"),
+            Error::Unsupported(msg) => write!(f, "{msg}"),
+            Error::Config(msg) => write!(f, "invalid config: {msg}"),
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Json(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Json(err)
+    }
+}
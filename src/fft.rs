@@ -0,0 +1,90 @@
+use std::sync::Arc;
+
+use na::{Complex, DMatrix};
+use rustfft::{Fft, FftDirection, FftPlanner};
+
+/// Plans of the 2d discrete Fourier transforms of nrows x ncols matrices
+///
+/// Planning is done once, so that the plans can be reused for
+/// every matrix of the same shape.
+#[derive(Clone)]
+pub struct Fft2 {
+    nrows: usize,
+    ncols: usize,
+    // (columns, rows) plans of each direction
+    forward: (Arc<dyn Fft<f64>>, Arc<dyn Fft<f64>>),
+    inverse: (Arc<dyn Fft<f64>>, Arc<dyn Fft<f64>>),
+}
+
+impl Fft2 {
+    pub fn new(nrows: usize, ncols: usize) -> Self {
+        let mut planner = FftPlanner::new();
+
+        Fft2 { nrows, ncols,
+               forward: (planner.plan_fft_forward(nrows), planner.plan_fft_forward(ncols)),
+               inverse: (planner.plan_fft_inverse(nrows), planner.plan_fft_inverse(ncols)) }
+    }
+
+    /// Shape (nrows, ncols) of the transformed matrices
+    pub fn shape(&self) -> (usize, usize) {
+        (self.nrows, self.ncols)
+    }
+
+    /// In-place 2d discrete Fourier transform of a matrix, see fft2
+    pub fn process(&self, mat: &mut DMatrix<Complex<f64>>, direction: FftDirection) {
+        assert!(mat.shape() == self.shape(), "mat shape != plan shape");
+
+        let (fft_col, fft_row) = match direction {
+            FftDirection::Forward => &self.forward,
+            FftDirection::Inverse => &self.inverse,
+        };
+
+        // columns are contiguous in nalgebra's column major storage
+        for mut col in mat.column_iter_mut() {
+            fft_col.process(col.as_mut_slice());
+        }
+
+        let mut buffer = vec![Complex::new(0., 0.); self.ncols];
+        for row in 0..self.nrows {
+            for col in 0..self.ncols {
+                buffer[col] = mat[(row, col)];
+            }
+            fft_row.process(&mut buffer);
+            for col in 0..self.ncols {
+                mat[(row, col)] = buffer[col];
+            }
+        }
+
+        if direction == FftDirection::Inverse {
+            *mat /= Complex::new((self.nrows*self.ncols) as f64, 0.);
+        }
+    }
+}
+
+/// In-place 2d discrete Fourier transform of a matrix
+///
+/// The inverse transform is normalised by 1/(nrows*ncols), so that
+/// a forward and an inverse transform return the original matrix.
+pub fn fft2(mat: &mut DMatrix<Complex<f64>>, direction: FftDirection) {
+    Fft2::new(mat.nrows(), mat.ncols()).process(mat, direction);
+}
+
+/// Periodic 2d convolution of two real matrices of the same shape
+///
+/// out[(y, x)] = sum over (ky, kx) of kernel[(ky, kx)]*mat[(y - ky, x - kx)]
+/// with periodic indices.
+pub fn convolve_periodic(mat: &DMatrix<f64>, kernel: &DMatrix<f64>) -> DMatrix<f64> {
+    assert!(mat.shape() == kernel.shape(), "mat shape != kernel shape");
+
+    let fft = Fft2::new(mat.nrows(), mat.ncols());
+    let mut mat_k = mat.map(|v| Complex::new(v, 0.));
+    let mut kernel_k = kernel.map(|v| Complex::new(v, 0.));
+
+    fft.process(&mut mat_k, FftDirection::Forward);
+    fft.process(&mut kernel_k, FftDirection::Forward);
+
+    let mut out = mat_k.component_mul(&kernel_k);
+    fft.process(&mut out, FftDirection::Inverse);
+
+    out.map(|v| v.re)
+}
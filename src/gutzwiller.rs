@@ -0,0 +1,193 @@
+use na::{DMatrix, DVector};
+
+use crate::dipolar::{DipolarSystem, get_dd_field, get_dd_field_fft, get_dd_kernel};
+
+/// Fraction of the new mean fields mixed into the old ones
+/// in every iteration, damps density wave oscillations
+pub const MIXING: f64 = 0.5;
+
+/// Site-resolved Gutzwiller mean-field state
+///
+/// All matrices are latt.size_y x latt.ncols(), like the occupation.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct GutzwillerState {
+    /// largest occupation of the local Fock space
+    pub n_max: usize,
+    /// superfluid order parameter <b> of each site
+    pub order_param: DMatrix<f64>,
+    /// mean occupation <n> of each site
+    pub density: DMatrix<f64>,
+    /// mean-field energy per site
+    pub energy: f64,
+    pub iterations: usize,
+    /// whether the mean fields changed less than the tolerance
+    pub converged: bool,
+}
+
+impl GutzwillerState {
+    /// Create an initial state from the occupation of the system with
+    /// a uniform seed of the order parameter
+    ///
+    /// The occupation selects the density wave that the solver starts
+    /// from, and the seed lets superfluid order grow.
+    pub fn new(dip: &DipolarSystem, n_max: usize, seed: f64) -> Self {
+        let density = dip.occupation.map(|n| (n as f64).min(n_max as f64));
+        let order_param = DMatrix::from_element(density.nrows(), density.ncols(), seed);
+
+        GutzwillerState { n_max, order_param, density, energy: 0.,
+                          iterations: 0, converged: false }
+    }
+
+    /// Largest absolute order parameter
+    pub fn max_order_param(&self) -> f64 {
+        self.order_param.amax()
+    }
+
+    /// Mean occupation per site
+    pub fn mean_density(&self) -> f64 {
+        self.density.mean()
+    }
+}
+
+/// Local mean-field Hamiltonian of a site in the Fock basis 0..=n_max
+///
+/// # Parameters:
+/// * mu - local chemical potential, mu minus the site potential
+/// * t - tunneling
+/// * psi - sum of the order parameters of the neighbours
+/// * dd_field - d-d interaction with the mean occupation of the other sites
+pub fn generate_local_hamiltonian(mu: f64, t: f64, psi: f64, dd_field: f64,
+                                  u_onsite: f64, n_max: usize) -> DMatrix<f64> {
+    DMatrix::from_fn(n_max + 1, n_max + 1, |row, col| {
+        let n = row as f64;
+
+        if row == col {
+            0.5*u_onsite*n*(n - 1.) - mu*n + dd_field*n
+        } else if row + 1 == col {
+            -t*psi*(n + 1.).sqrt()
+        } else if col + 1 == row {
+            -t*psi*n.sqrt()
+        } else {
+            0.
+        }
+    })
+}
+
+/// Get the ground state Fock coefficients of a local Hamiltonian
+fn get_local_ground_state(h_local: DMatrix<f64>) -> DVector<f64> {
+    let eigen = h_local.symmetric_eigen();
+    let imin = eigen.eigenvalues.imin();
+
+    eigen.eigenvectors.column(imin).into_owned()
+}
+
+/// Get <b>, <n> and <n(n-1)> of a local state
+fn get_local_expectations(coeffs: &DVector<f64>) -> (f64, f64, f64) {
+    let n_max = coeffs.len() - 1;
+    let mut order_param = 0.;
+    let mut density = 0.;
+    let mut pairs = 0.;
+
+    for n in 0..n_max + 1 {
+        let prob = coeffs[n].powi(2);
+        density += n as f64*prob;
+        pairs += (n*n.saturating_sub(1)) as f64*prob;
+
+        if n < n_max {
+            order_param += coeffs[n]*coeffs[n + 1]*((n + 1) as f64).sqrt();
+        }
+    }
+
+    (order_param.abs(), density, pairs)
+}
+
+/// Sum of the order parameters of the neighbours of each site,
+/// weighted with the relative tunneling of the bonds
+fn get_neighbour_sum(order_param: &DMatrix<f64>, dip: &DipolarSystem) -> DMatrix<f64> {
+    DMatrix::from_fn(order_param.nrows(), order_param.ncols(), |y, x| {
+        dip.get_hoppings(x, y).into_iter()
+            .map(|(x_n, y_n, bond)| bond*order_param[(y_n, x_n)])
+            .sum()
+    })
+}
+
+/// Iterate the site-resolved Gutzwiller mean-field equations of the
+/// dipolar Bose-Hubbard model to self-consistency
+///
+/// The tunneling is decoupled as b_i^+ b_j -> <b_i>* b_j + b_i^+ <b_j>
+/// and the d-d interaction enters as the Hartree field of the mean
+/// occupation within int_range, as in dd_mat. Each site is then a
+/// local Hamiltonian in the Fock space truncated at state.n_max, whose
+/// ground state gives the new order parameter and density. The
+/// neighbours of each site are the ones of get_hoppings.
+///
+/// The iteration starts from state and stops when the largest change
+/// of the order parameter and the density is below tolerance or after
+/// max_iter steps.
+///
+/// # Parameters:
+/// * mu - chemical potential
+/// * t - tunneling
+pub fn solve_gutzwiller(mu: f64, t: f64, dip: &DipolarSystem, state: GutzwillerState,
+                        tolerance: f64, max_iter: usize) -> GutzwillerState {
+    let latt = &dip.latt;
+    let n_max = state.n_max;
+    let mut order_param = state.order_param;
+    let mut density = state.density;
+
+    // the kernel only needs to be computed once
+    let kernel = get_dd_kernel(dip).ok();
+    let get_field = |density: &DMatrix<f64>| kernel.as_ref()
+        .and_then(|kernel| get_dd_field_fft(density, dip, kernel).ok())
+        .unwrap_or_else(|| get_dd_field(density, dip));
+
+    let mut iterations = 0;
+    let mut converged = false;
+
+    while iterations < max_iter && !converged {
+        let psi = get_neighbour_sum(&order_param, dip);
+        let dd_field = get_field(&density);
+        let mut change: f64 = 0.;
+
+        for x in 0..latt.ncols() {
+            for y in 0..latt.size_y {
+                let h_local = generate_local_hamiltonian(mu - dip.potential[(y, x)], t, psi[(y, x)], dd_field[(y, x)],
+                                                         dip.u_onsite, n_max);
+                let (order_param_new, density_new, _) =
+                    get_local_expectations(&get_local_ground_state(h_local));
+
+                change = change
+                    .max((order_param_new - order_param[(y, x)]).abs())
+                    .max((density_new - density[(y, x)]).abs());
+
+                order_param[(y, x)] += MIXING*(order_param_new - order_param[(y, x)]);
+                density[(y, x)] += MIXING*(density_new - density[(y, x)]);
+            }
+        }
+
+        iterations += 1;
+        converged = change < tolerance;
+    }
+
+    // energy of the Gutzwiller product state of the final mean fields
+    let psi = get_neighbour_sum(&order_param, dip);
+    let dd_field = get_field(&density);
+    let mut energy = 0.;
+
+    for x in 0..latt.ncols() {
+        for y in 0..latt.size_y {
+            let h_local = generate_local_hamiltonian(mu - dip.potential[(y, x)], t, psi[(y, x)], dd_field[(y, x)],
+                                                     dip.u_onsite, n_max);
+            let (order_param_site, density_site, pairs) =
+                get_local_expectations(&get_local_ground_state(h_local));
+
+            // bonds and d-d pairs are shared by two sites
+            energy += 0.5*dip.u_onsite*pairs - (mu - dip.potential[(y, x)])*density_site
+                - t*order_param_site*psi[(y, x)] + 0.5*density_site*dd_field[(y, x)];
+        }
+    }
+
+    GutzwillerState { n_max, order_param, density, energy: energy/latt.no_sites() as f64,
+                      iterations, converged }
+}
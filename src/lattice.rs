@@ -1,38 +1,480 @@
-use na::DMatrix;
+use na::{DMatrix, Vector3};
 
-/// Struct that holds periodic 2d lattice information
-#[derive(Debug)]
+use crate::error::{Error, Result};
+
+/// Lattice geometry, defined by two primitive vectors and a basis
+///
+/// All geometries have a nearest neighbour distance of 1.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Geometry {
+    Square,
+    Triangular,
+    Honeycomb,
+    Kagome,
+}
+
+impl Geometry {
+    /// Get the primitive vectors (a1, a2)
+    pub fn primitive_vectors(&self) -> (Vector3<f64>, Vector3<f64>) {
+        let sqrt3 = 3f64.sqrt();
+
+        match self {
+            Geometry::Square => (Vector3::new(1., 0., 0.), Vector3::new(0., 1., 0.)),
+            Geometry::Triangular => (Vector3::new(1., 0., 0.), Vector3::new(0.5, 0.5*sqrt3, 0.)),
+            Geometry::Honeycomb => (Vector3::new(sqrt3, 0., 0.), Vector3::new(0.5*sqrt3, 1.5, 0.)),
+            Geometry::Kagome => (Vector3::new(2., 0., 0.), Vector3::new(1., sqrt3, 0.)),
+        }
+    }
+
+    /// Get the positions of the basis sites within the unit cell
+    pub fn basis(&self) -> Vec<Vector3<f64>> {
+        match self {
+            Geometry::Square | Geometry::Triangular => vec![Vector3::zeros()],
+            Geometry::Honeycomb => vec![Vector3::zeros(), Vector3::new(0., 1., 0.)],
+            Geometry::Kagome => vec![Vector3::zeros(), Vector3::new(1., 0., 0.),
+                                     Vector3::new(0.5, 0.5*3f64.sqrt(), 0.)],
+        }
+    }
+
+    /// Number of basis sites
+    pub fn n_basis(&self) -> usize {
+        match self {
+            Geometry::Square | Geometry::Triangular => 1,
+            Geometry::Honeycomb => 2,
+            Geometry::Kagome => 3,
+        }
+    }
+
+    /// Area of the unit cell
+    pub fn cell_area(&self) -> f64 {
+        let (a1, a2) = self.primitive_vectors();
+        a1.cross(&a2).norm()
+    }
+
+    /// Get the distance vector from basis site b of the cell at the origin
+    /// to basis site b_n of the cell at n1*a1 + n2*a2
+    pub fn get_dist_vec(&self, n1: isize, n2: isize, b: usize, b_n: usize) -> Vector3<f64> {
+        let (a1, a2) = self.primitive_vectors();
+        let basis = self.basis();
+
+        a1*n1 as f64 + a2*n2 as f64 + basis[b_n] - basis[b]
+    }
+
+    /// Get the number of cells (n1_max, n2_max) along a1 and a2 which
+    /// contains all sites within the given radius of any basis site
+    pub fn get_cell_window(&self, radius: f64) -> (isize, isize) {
+        let (a1, a2) = self.primitive_vectors();
+        let basis_extent = self.basis().iter().map(|b| b.norm()).fold(0., f64::max);
+        let reach = radius + 2.*basis_extent;
+
+        // distance between neighbouring lattice lines along a2 is area/|a1|
+        ((reach*a2.norm()/self.cell_area() + 1e-9).floor() as isize,
+         (reach*a1.norm()/self.cell_area() + 1e-9).floor() as isize)
+    }
+
+    /// Distance of the next-nearest neighbours
+    pub fn nnn_distance(&self) -> f64 {
+        match self {
+            Geometry::Square => 2f64.sqrt(),
+            Geometry::Triangular | Geometry::Honeycomb | Geometry::Kagome => 3f64.sqrt(),
+        }
+    }
+
+    /// Get the nearest neighbours of each basis site as
+    /// (n1, n2, b_n) cell offsets and basis index
+    pub fn neighbour_offsets(&self) -> Vec<Vec<(isize, isize, usize)>> {
+        self.get_shell_offsets(1.)
+    }
+
+    /// Get the next-nearest neighbours of each basis site,
+    /// see neighbour_offsets
+    pub fn nnn_offsets(&self) -> Vec<Vec<(isize, isize, usize)>> {
+        self.get_shell_offsets(self.nnn_distance())
+    }
+
+    fn get_shell_offsets(&self, distance: f64) -> Vec<Vec<(isize, isize, usize)>> {
+        let n_basis = self.n_basis();
+
+        (0..n_basis).map(|b| {
+            let mut offsets = Vec::new();
+            for n1 in -2..3 {
+                for n2 in -2..3 {
+                    for b_n in 0..n_basis {
+                        if (self.get_dist_vec(n1, n2, b, b_n).norm() - distance).abs() < 1e-9 {
+                            offsets.push((n1, n2, b_n));
+                        }
+                    }
+                }
+            }
+            offsets
+        }).collect()
+    }
+}
+
+/// Boundary conditions of a lattice
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Boundary {
+    /// periodic along both directions (torus)
+    Periodic,
+    /// open along both directions
+    Open,
+    /// periodic along x, open along y
+    CylinderX,
+    /// periodic along y, open along x
+    CylinderY,
+    /// periodic along both directions, where a hop across the x (y)
+    /// boundary picks up a twist angle of pi, i.e. flips the sign of the
+    /// tunneling, if twist_x (twist_y) is set
+    Twisted { twist_x: bool, twist_y: bool },
+}
+
+impl Boundary {
+    /// Whether the sites wrap around along both directions, so that
+    /// the d-d interaction is translation invariant
+    pub fn is_periodic(&self) -> bool {
+        matches!(self, Boundary::Periodic | Boundary::Twisted { .. })
+    }
+}
+
+/// Stacking of copies of the 2d lattice along z
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stacking {
+    /// given number of layers at the given spacing, open along z
+    Open(usize, f64),
+    /// given number of layers at the given spacing, periodic along z
+    Periodic(usize, f64),
+    /// size_x layers at spacing 1, periodic along z, which gives
+    /// a simple cubic lattice for the square geometry
+    Cubic,
+}
+
+/// Struct that holds periodic lattice information
+///
+/// The boundary conditions are periodic unless set with with_boundary,
+/// and the lattice is a single layer unless set with with_stacking.
+///
+/// The lattice has size_x x size_y unit cells. Every cell holds
+/// the basis sites of all layers, with cell site index
+/// s = layer*n_basis + b. Sites are indexed by the position (x, y),
+/// where y is the cell index along a2 and x = cell_x*n_cell_sites + s
+/// combines the cell index along a1 with the cell site index, so that
+/// lattice matrices are size_y x (size_x*n_cell_sites).
+#[derive(Debug, Clone)]
 #[non_exhaustive]
 pub struct PeriodicLattice 
 {
-    pub system_size: usize, // the system is a square
+    pub size_x: usize,
+    pub size_y: usize,
+    pub geometry: Geometry,
+    pub boundary: Boundary,
+    pub stacking: Stacking,
+    // cached neighbour offsets of each cell site
+    neighbour_offsets: Vec<Vec<(isize, isize, usize)>>,
+    // cached next-nearest neighbour offsets of each cell site
+    nnn_offsets: Vec<Vec<(isize, isize, usize)>>,
 }
 
 impl PeriodicLattice {
-    /// Get a periodic lattice index
+    /// Get a periodic lattice index along x
     /// from a bare lattice index
     /// 
     /// # Examples
     /// ```
     /// use mean_field_dipolar::lattice::PeriodicLattice;
-    /// let system = PeriodicLattice::new(4);
+    /// let system = PeriodicLattice::new(4).unwrap();
     /// let idx = 5;
-    /// assert_eq!(1, system.get_idx_periodic(idx));
+    /// assert_eq!(1, system.get_x_periodic(idx));
     /// ```
-    pub fn get_idx_periodic(&self, idx: isize) -> usize {
+    pub fn get_x_periodic(&self, idx: isize) -> usize {
+        Self::get_idx_periodic(idx, self.ncols())
+    }
+
+    /// Get a periodic lattice index along y
+    /// from a bare lattice index
+    pub fn get_y_periodic(&self, idx: isize) -> usize {
+        Self::get_idx_periodic(idx, self.size_y)
+    }
+
+    fn get_idx_periodic(idx: isize, size: usize) -> usize {
         // can get a negative argument
         // convert system size into isize first and then
         // convert the result to usize
-        (idx.rem_euclid(self.system_size.try_into().unwrap()))
+        (idx.rem_euclid(size.try_into().unwrap()))
             .try_into().unwrap()
     }
 
-    pub fn new(system_size: usize) -> Self {
-        if system_size > isize::MAX.try_into().unwrap() {
-            panic!("Given system_size needs to be less than {}", isize::MAX);
+    /// Number of lattice sites
+    pub fn no_sites(&self) -> usize {
+        self.ncols()*self.size_y
+    }
+
+    /// Number of columns of lattice matrices
+    pub fn ncols(&self) -> usize {
+        self.size_x*self.n_cell_sites()
+    }
+
+    /// Number of layers along z
+    pub fn layers(&self) -> usize {
+        match self.stacking {
+            Stacking::Open(layers, _) | Stacking::Periodic(layers, _) => layers,
+            Stacking::Cubic => self.size_x,
         }
+    }
+
+    /// Distance between neighbouring layers
+    pub fn layer_spacing(&self) -> f64 {
+        match self.stacking {
+            Stacking::Open(_, spacing) | Stacking::Periodic(_, spacing) => spacing,
+            Stacking::Cubic => 1.,
+        }
+    }
+
+    /// Whether the stacking is periodic along z
+    pub fn is_periodic_z(&self) -> bool {
+        matches!(self.stacking, Stacking::Periodic(..) | Stacking::Cubic)
+    }
+
+    /// Period of the lattice along z, layers*layer_spacing
+    pub fn period_z(&self) -> f64 {
+        self.layers() as f64*self.layer_spacing()
+    }
+
+    /// Number of sites in a unit cell, the basis sites of all layers
+    pub fn n_cell_sites(&self) -> usize {
+        self.geometry.n_basis()*self.layers()
+    }
+
+    /// Get the positions of the sites within the unit cell
+    pub fn cell_sites(&self) -> Vec<Vector3<f64>> {
+        let basis = self.geometry.basis();
+        let spacing = self.layer_spacing();
+
+        (0..self.layers())
+            .flat_map(|layer| basis.iter().map(move |b| b + Vector3::new(0., 0., layer as f64*spacing)))
+            .collect()
+    }
+
+    /// Get the number of periodic images n3_max along z which contains
+    /// all sites within the given radius, 0 for open stacking
+    pub fn get_image_window(&self, radius: f64) -> isize {
+        if self.is_periodic_z() {
+            // layer offsets within the cell are below the period
+            (radius/self.period_z() + 1e-9).floor() as isize + 1
+        } else {
+            0
+        }
+    }
 
-        PeriodicLattice { system_size }
+    /// Get the nearest neighbours of each cell site as
+    /// (n1, n2, s_n) cell offsets and cell site index
+    ///
+    /// In-plane neighbours are the ones of the geometry, interlayer
+    /// neighbours are the same basis site in the adjacent layers.
+    pub fn neighbour_offsets(&self) -> &[Vec<(isize, isize, usize)>] {
+        &self.neighbour_offsets
+    }
+
+    /// Get the in-plane next-nearest neighbours of each cell site,
+    /// see neighbour_offsets
+    pub fn nnn_offsets(&self) -> &[Vec<(isize, isize, usize)>] {
+        &self.nnn_offsets
+    }
+
+    /// Get the vector from cell site s to the cell site s_n of the
+    /// cell at (n1, n2) cells
+    pub fn get_bond_vec(&self, s: usize, n1: isize, n2: isize, s_n: usize) -> Vector3<f64> {
+        let (a1, a2) = self.geometry.primitive_vectors();
+        let cell_sites = self.cell_sites();
+
+        a1*n1 as f64 + a2*n2 as f64 + cell_sites[s_n] - cell_sites[s]
+    }
+
+    fn get_nnn_offsets(&self) -> Vec<Vec<(isize, isize, usize)>> {
+        let n_basis = self.geometry.n_basis();
+        let in_plane = self.geometry.nnn_offsets();
+
+        (0..self.n_cell_sites()).map(|s| {
+            let (layer, b) = (s/n_basis, s%n_basis);
+            in_plane[b].iter()
+                .map(|&(n1, n2, b_n)| (n1, n2, layer*n_basis + b_n))
+                .collect()
+        }).collect()
+    }
+
+    fn get_neighbour_offsets(&self) -> Vec<Vec<(isize, isize, usize)>> {
+        let n_basis = self.geometry.n_basis();
+        let layers = self.layers() as isize;
+        let in_plane = self.geometry.neighbour_offsets();
+
+        (0..self.n_cell_sites()).map(|s| {
+            let (layer, b) = ((s/n_basis) as isize, s%n_basis);
+            let mut offsets: Vec<_> = in_plane[b].iter()
+                .map(|&(n1, n2, b_n)| (n1, n2, layer as usize*n_basis + b_n))
+                .collect();
+
+            for layer_n in [layer - 1, layer + 1] {
+                let layer_n = if self.is_periodic_z() { layer_n.rem_euclid(layers) } else { layer_n };
+
+                // with two periodic layers both directions reach the same layer
+                if layer_n != layer && (0..layers).contains(&layer_n) {
+                    let offset = (0, 0, layer_n as usize*n_basis + b);
+                    if !offsets.contains(&offset) {
+                        offsets.push(offset);
+                    }
+                }
+            }
+            offsets
+        }).collect()
+    }
+
+    /// Get the position of the site at (n1, n2) cells and cell site s_n
+    /// from the site at (x, y), wrapped according to the boundary
+    ///
+    /// Returns None if the site lies outside of an open boundary.
+    pub fn get_offset_pos(&self, x: usize, y: usize, n1: isize, n2: isize, s_n: usize) -> Option<(usize, usize)> {
+        let n_cell_sites = self.n_cell_sites() as isize;
+        let size_x = self.size_x as isize;
+        let size_y = self.size_y as isize;
+
+        let cell_x = (x as isize)/n_cell_sites + n1;
+        let cell_y = y as isize + n2;
+
+        let (periodic_x, periodic_y) = match self.boundary {
+            Boundary::Periodic | Boundary::Twisted { .. } => (true, true),
+            Boundary::Open => (false, false),
+            Boundary::CylinderX => (true, false),
+            Boundary::CylinderY => (false, true),
+        };
+
+        if (!periodic_x && !(0..size_x).contains(&cell_x))
+            || (!periodic_y && !(0..size_y).contains(&cell_y)) {
+            return None
+        }
+
+        Some((self.get_x_periodic(cell_x*n_cell_sites + s_n as isize),
+              self.get_y_periodic(cell_y)))
+    }
+
+    /// Get the sign of the tunneling from site (x, y) to the site at
+    /// (n1, n2) cells, see get_offset_pos
+    ///
+    /// The sign is -1 if the hop crosses twisted boundaries an odd
+    /// number of times and 1 otherwise.
+    pub fn get_twist_sign(&self, x: usize, y: usize, n1: isize, n2: isize) -> f64 {
+        let Boundary::Twisted { twist_x, twist_y } = self.boundary else { return 1. };
+
+        let wraps_x = ((x/self.n_cell_sites()) as isize + n1).div_euclid(self.size_x as isize);
+        let wraps_y = (y as isize + n2).div_euclid(self.size_y as isize);
+        let wraps = if twist_x { wraps_x } else { 0 } + if twist_y { wraps_y } else { 0 };
+
+        if wraps%2 == 0 { 1. } else { -1. }
+    }
+
+    /// Get the positions of the nearest neighbours of site (x, y)
+    pub fn get_neighbours(&self, x: usize, y: usize) -> Vec<(usize, usize)> {
+        let s = x%self.n_cell_sites();
+
+        self.neighbour_offsets[s].iter()
+            .filter_map(|&(n1, n2, s_n)| self.get_offset_pos(x, y, n1, n2, s_n))
+            .collect()
+    }
+
+    /// Set the boundary conditions of the lattice
+    ///
+    /// Returns an error for a twisted direction of fewer than 3 cells,
+    /// where the direct and the wrapped hop reach the same site.
+    pub fn with_boundary(mut self, boundary: Boundary) -> Result<Self> {
+        if let Boundary::Twisted { twist_x, twist_y } = boundary {
+            if (twist_x && self.size_x < 3) || (twist_y && self.size_y < 3) {
+                return Err(Error::Config(format!("twisted boundaries need at least 3 cells along the twist, \
+                                                  the lattice has {}x{}", self.size_x, self.size_y)))
+            }
+        }
+        self.boundary = boundary;
+        Ok(self)
+    }
+
+    /// Set the stacking of layers along z
+    ///
+    /// Returns an error if there are no layers or too many sites.
+    pub fn with_stacking(mut self, stacking: Stacking) -> Result<Self> {
+        self.stacking = stacking;
+        if self.layers() == 0 {
+            return Err(Error::Config("the number of layers needs to be positive".to_string()))
+        }
+        Self::check_no_sites(self.size_x, self.size_y, self.n_cell_sites())?;
+
+        self.neighbour_offsets = self.get_neighbour_offsets();
+        self.nnn_offsets = self.get_nnn_offsets();
+        Ok(self)
+    }
+
+    fn check_no_sites(size_x: usize, size_y: usize, n_cell_sites: usize) -> Result<()> {
+        if size_x == 0 || size_y == 0 {
+            return Err(Error::Config(format!("lattice size {size_x}x{size_y} needs to be positive")))
+        }
+        if size_x.checked_mul(size_y)
+            .and_then(|n| n.checked_mul(n_cell_sites))
+            .is_none_or(|n| n > isize::MAX as usize) {
+            return Err(Error::Config(format!("number of sites needs to be less than {}", isize::MAX)))
+        }
+        Ok(())
+    }
+
+    /// Create a square lattice
+    ///
+    /// Returns an error if the size is 0 or there are too many sites.
+    pub fn new(system_size: usize) -> Result<Self> {
+        Self::new_rect(system_size, system_size)
+    }
+
+    /// Create a rectangular size_x x size_y lattice, see new
+    pub fn new_rect(size_x: usize, size_y: usize) -> Result<Self> {
+        Self::new_geometry(Geometry::Square, size_x, size_y)
+    }
+
+    /// Create a lattice of size_x x size_y unit cells of the given geometry, see new
+    pub fn new_geometry(geometry: Geometry, size_x: usize, size_y: usize) -> Result<Self> {
+        Self::check_no_sites(size_x, size_y, geometry.n_basis())?;
+
+        Ok(PeriodicLattice { size_x, size_y, geometry, boundary: Boundary::Periodic,
+                             stacking: Stacking::Open(1, 1.),
+                             neighbour_offsets: geometry.neighbour_offsets(),
+                             nnn_offsets: geometry.nnn_offsets() })
+    }
+
+    /// Create a simple cubic lattice of size^3 sites, see new
+    pub fn new_cubic(size: usize) -> Result<Self> {
+        Self::new(size)?.with_stacking(Stacking::Cubic)
+    }
+
+    /// Get the spin index of site (y, x), see SpinIdx
+    pub fn get_spin_idx(&self, x: usize, y: usize) -> usize {
+        y*self.ncols() + x
+    }
+
+    /// Get the site (x, y) of a spin index, see SpinIdx
+    pub fn get_spin_pos(&self, idx: usize) -> (usize, usize) {
+        (idx%self.ncols(), idx/self.ncols())
+    }
+
+    /// Get the position of site (y, x), with the first cell at the origin
+    pub fn get_site_pos(&self, x: usize, y: usize) -> Vector3<f64> {
+        let (a1, a2) = self.geometry.primitive_vectors();
+        let n_cell_sites = self.n_cell_sites();
+
+        a1*(x/n_cell_sites) as f64 + a2*y as f64 + self.cell_sites()[x%n_cell_sites]
+    }
+
+    /// Get the column of site x in its layer, as if the lattice
+    /// were a single layer
+    pub fn get_layer_col(&self, x: usize) -> usize {
+        let n_basis = self.geometry.n_basis();
+
+        (x/self.n_cell_sites())*n_basis + x%n_basis
     }
 }
 
@@ -48,13 +490,13 @@ pub struct LattPos<'a> {
 impl <'a> LattPos<'a> {
     /// Create a new lattice position
     /// 
-    /// Panics if the given lattice indices are not compatible with
-    /// latt.system_size.
-    pub fn new(x: usize, y: usize, latt: &PeriodicLattice) -> LattPos {
-        if x >= latt.system_size || y >= latt.system_size {
-            panic!("Given indices not compatible with the given system size.");
+    /// Returns an error if the given lattice indices are not compatible
+    /// with latt.ncols() and latt.size_y.
+    pub fn new(x: usize, y: usize, latt: &PeriodicLattice) -> Result<LattPos<'_>> {
+        if x >= latt.ncols() || y >= latt.size_y {
+            return Err(Error::Position { x, y })
         }
-        LattPos { x: x, y: y, latt: latt }
+        Ok(LattPos { x, y, latt })
     }
 }
 
@@ -69,49 +511,99 @@ pub struct SpinIdx<'a> {
 impl <'a> SpinIdx<'a> {
     /// Create a new spin index
     /// 
-    /// Panics if the index is not compatible with the given lattice.
-    pub fn new(idx: usize, latt: &PeriodicLattice) -> SpinIdx {
-        if idx >= latt.system_size.pow(2) {
-            panic!("Index not compatible with the given system size");
+    /// Returns an error if the index is not compatible with the given lattice.
+    pub fn new(idx: usize, latt: &PeriodicLattice) -> Result<SpinIdx<'_>> {
+        if idx >= latt.no_sites() {
+            return Err(Error::SpinIdx { idx, no_sites: latt.no_sites() })
         }
-        SpinIdx {idx, latt}
+        Ok(SpinIdx {idx, latt})
     }
 }
 
 impl <'a> From<SpinIdx<'a>> for LattPos<'a> {
     /// Get a lattice position from a spin index
     fn from(sp: SpinIdx) -> LattPos {
-        LattPos { x: sp.idx%sp.latt.system_size,
-                  y: sp.idx/sp.latt.system_size,
-                  latt: sp.latt }
+        let (x, y) = sp.latt.get_spin_pos(sp.idx);
+        LattPos { x, y, latt: sp.latt }
     }
 }
 
 impl <'a> From<&SpinIdx<'a>> for LattPos<'a> {
     /// Get a lattice position from a reference to a spin index
     fn from(sp: &SpinIdx<'a>) -> LattPos<'a> {
-        LattPos { x: sp.idx%sp.latt.system_size,
-                  y: sp.idx/sp.latt.system_size,
-                  latt: sp.latt }
+        let (x, y) = sp.latt.get_spin_pos(sp.idx);
+        LattPos { x, y, latt: sp.latt }
     }
 }
 
 impl <'a> From<LattPos<'a>> for SpinIdx<'a> {
     /// Get a spin index from a position in the lattice
     fn from(pos: LattPos) -> SpinIdx {
-        SpinIdx {idx: pos.y*pos.latt.system_size + pos.x,
+        SpinIdx {idx: pos.latt.get_spin_idx(pos.x, pos.y),
                  latt: pos.latt}
     }
 }
 
+/// Patterns of stacked layers repeat the same pattern in every layer
 pub fn get_checkerboard(latt: &PeriodicLattice) -> DMatrix<u8> {
-    DMatrix::from_fn(latt.system_size, latt.system_size, |row, col| ((row + col)%2) as u8)
+    DMatrix::from_fn(latt.size_y, latt.ncols(), |row, col| ((row + latt.get_layer_col(col))%2) as u8)
 }
 
 pub fn get_filled(latt: &PeriodicLattice) -> DMatrix<u8> {
-    DMatrix::repeat(latt.system_size, latt.system_size, 1)
+    DMatrix::repeat(latt.size_y, latt.ncols(), 1)
 }
 
 pub fn get_horizontal_stripe(latt: &PeriodicLattice) -> DMatrix<u8> {
-    DMatrix::from_fn(latt.system_size, latt.system_size, |row, _| (row%2) as u8)
-}
\ No newline at end of file
+    DMatrix::from_fn(latt.size_y, latt.ncols(), |row, _| (row%2) as u8)
+}
+
+/// Set the occupied sites of a 0/1 pattern to n_a and the empty ones to n_b
+///
+/// # Examples
+/// ```
+/// use mean_field_dipolar::lattice::{PeriodicLattice, fill_pattern, get_checkerboard};
+/// let latt = PeriodicLattice::new(4).unwrap();
+/// let occupation = fill_pattern(&get_checkerboard(&latt), (2, 1));
+/// assert_eq!(occupation[(0, 1)], 2);
+/// assert_eq!(occupation[(0, 0)], 1);
+/// ```
+pub fn fill_pattern(pattern: &DMatrix<u8>, fillings: (u8, u8)) -> DMatrix<u8> {
+    pattern.map(|n| if n > 0 { fillings.0 } else { fillings.1 })
+}
+
+/// Get the occupation of the next lobe in mu after occupation
+///
+/// A particle is added to the least occupied sites, or, if occupation
+/// is uniform, to the sites that are most occupied in pattern, so that
+/// e.g. the 1/0 checkerboard is followed by the filled lattice, the 2/1
+/// checkerboard and the lattice filled with 2.
+///
+/// Returns an error if an occupation exceeds u8::MAX.
+pub fn get_next_lobe(occupation: &DMatrix<u8>, pattern: &DMatrix<u8>) -> Result<DMatrix<u8>> {
+    let (sites, n_add) = if occupation.min() < occupation.max() {
+        (occupation, occupation.min())
+    } else {
+        (pattern, pattern.max())
+    };
+
+    occupation.zip_map(sites, |n, n_site| if n_site == n_add { n.checked_add(1) } else { Some(n) })
+        .iter()
+        .map(|n| n.ok_or_else(|| Error::Config(format!("lobe occupation exceeds {}", u8::MAX))))
+        .collect::<Result<Vec<u8>>>()
+        .map(|occupation_new| DMatrix::from_vec(occupation.nrows(), occupation.ncols(), occupation_new))
+}
+
+/// Get the harmonic trap potential strength*r^2, with r the in-plane
+/// distance of a site from centre
+///
+/// The centre defaults to the mean position of all sites.
+pub fn get_harmonic_potential(latt: &PeriodicLattice, strength: f64, centre: Option<(f64, f64)>) -> DMatrix<f64> {
+    let positions = DMatrix::from_fn(latt.size_y, latt.ncols(), |y, x| latt.get_site_pos(x, y));
+
+    let (centre_x, centre_y) = centre.unwrap_or_else(|| {
+        let mean = positions.iter().sum::<Vector3<f64>>()/latt.no_sites() as f64;
+        (mean.x, mean.y)
+    });
+
+    positions.map(|pos| strength*((pos.x - centre_x).powi(2) + (pos.y - centre_y).powi(2)))
+}
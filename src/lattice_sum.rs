@@ -0,0 +1,151 @@
+use std::f64::consts::PI;
+
+use na::{DMatrix, Vector3};
+
+use crate::dipolar::{DdKernel, DipolarSystem, convolve_dd_kernel, get_dd_int, get_dd_kernel};
+use crate::error::{Error, Result};
+
+/// Largest cutoff radius used by get_dd_kernel_lattice_sum
+pub const MAX_CUTOFF: f64 = 1024.;
+
+/// Smooth cutoff function, 1 below u=1, 0 above u=2 and
+/// infinitely differentiable in between
+pub fn smooth_cutoff(u: f64) -> f64 {
+    if u <= 1. {
+        1.
+    } else if u >= 2. {
+        0.
+    } else {
+        let x = u - 1.;
+        let a = (-1./x).exp();
+        let b = (-1./(1. - x)).exp();
+        b/(a + b)
+    }
+}
+
+/// Integral of (1 - smooth_cutoff(u))/u^2 from 0 to infinity
+fn smooth_cutoff_tail() -> f64 {
+    // Simpson rule on [1, 2], the integrand is 1/u^2 above
+    let no_intervals = 1000;
+    let h = 1./no_intervals as f64;
+    let integrand = |u: f64| (1. - smooth_cutoff(u))/u.powi(2);
+
+    let inner: f64 = (1..no_intervals)
+        .map(|idx| {
+            let weight = if idx%2 == 1 { 4. } else { 2. };
+            weight*integrand(1. + idx as f64*h)
+        })
+        .sum();
+
+    (integrand(1.) + inner + integrand(2.))*h/3. + 0.5
+}
+
+/// Periodic d-d kernel summed over all lattice vectors d != 0,
+/// weighted with smooth_cutoff(|d|/cutoff), plus the continuum
+/// estimate of the remaining tail. The kernel has the same layout
+/// as the blocks of the one from get_dd_kernel.
+///
+/// Unlike get_dd_kernel, the periodic images of the site itself are
+/// included, as they are distinct sites of the infinite crystal.
+/// The tail is the interaction with a uniform density weighted with
+/// 1 - smooth_cutoff, which is spread evenly over the kernel so that
+/// it couples to the mean occupation. For periodic stacking the sum
+/// runs over a sphere in 3d, where the angular average of the tail
+/// vanishes. Thanks to the smooth cutoff the error decays faster than
+/// any power of the cutoff.
+pub fn get_dd_kernel_cutoff(dip: &DipolarSystem, cutoff: f64) -> Vec<DMatrix<f64>> {
+    let latt = &dip.latt;
+    let geometry = &latt.geometry;
+    let n_cell_sites = latt.n_cell_sites();
+    let (n1_max, n2_max) = geometry.get_cell_window(2.*cutoff);
+    let n3_max = latt.get_image_window(2.*cutoff);
+    let (a1, a2) = geometry.primitive_vectors();
+    let a3 = Vector3::new(0., 0., latt.period_z());
+    let cell_sites = latt.cell_sites();
+    let dip_vec = dip.get_dipole_vec();
+
+    let tail = if latt.is_periodic_z() {
+        0.
+    } else {
+        2.*PI*(1. - 1.5*dip.theta.sin().powi(2))*smooth_cutoff_tail()
+            /(cutoff*geometry.cell_area())
+    };
+    let no_cells = latt.size_x*latt.size_y;
+    let mut kernel = vec![DMatrix::from_element(latt.size_y, latt.size_x, tail/no_cells as f64);
+                          n_cell_sites*n_cell_sites];
+
+    for s in 0..n_cell_sites {
+        for s_n in 0..n_cell_sites {
+            for n1 in -n1_max..n1_max + 1 {
+                for n2 in -n2_max..n2_max + 1 {
+                    for n3 in -n3_max..n3_max + 1 {
+                        let dist_vec = a1*n1 as f64 + a2*n2 as f64 + a3*n3 as f64
+                            + cell_sites[s_n] - cell_sites[s];
+                        let weight = smooth_cutoff(dist_vec.norm()/cutoff);
+
+                        if !(n1 == 0 && n2 == 0 && n3 == 0 && s == s_n) && weight > 0. {
+                            let kx = (-n1).rem_euclid(latt.size_x as isize) as usize;
+                            let ky = latt.get_y_periodic(-n2);
+                            kernel[s*n_cell_sites + s_n][(ky, kx)] += weight*get_dd_int(dist_vec, dip_vec);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    kernel
+}
+
+/// Get the periodic d-d kernel of the complete lattice sum,
+/// independent of int_range.
+///
+/// The cutoff of get_dd_kernel_cutoff is doubled until the summed
+/// absolute change of the kernel, which bounds the change of the
+/// interaction per unit occupation, is below tolerance or the cutoff
+/// reaches MAX_CUTOFF.
+pub fn get_dd_kernel_lattice_sum(dip: &DipolarSystem, tolerance: f64) -> Vec<DMatrix<f64>> {
+    let mut cutoff = (dip.latt.size_x.max(dip.latt.size_y) as f64).max(8.);
+    let mut kernel = get_dd_kernel_cutoff(dip, cutoff);
+
+    while cutoff < MAX_CUTOFF {
+        cutoff *= 2.;
+        let next = get_dd_kernel_cutoff(dip, cutoff);
+        let change: f64 = next.iter().zip(&kernel)
+            .map(|(n, k)| (n - k).abs().sum())
+            .sum();
+
+        kernel = next;
+        if change < tolerance {
+            break
+        }
+    }
+
+    kernel
+}
+
+/// Generate the d-d interaction matrix from the complete periodic
+/// lattice sum, see get_dd_kernel_lattice_sum
+///
+/// Returns an error if the lattice boundary is not periodic.
+pub fn generate_dd_int_mat_lattice_sum(dip: &mut DipolarSystem, tolerance: f64) -> Result<()> {
+    if !dip.latt.boundary.is_periodic() {
+        return Err(Error::Unsupported("the lattice sum requires periodic boundaries"))
+    }
+    let kernel = DdKernel::new(get_dd_kernel_lattice_sum(dip, tolerance), &dip.latt)?;
+
+    dip.dd_mat = convolve_dd_kernel(dip, &kernel)?;
+    Ok(())
+}
+
+/// Get the error of the d-d interaction matrix truncated at int_range
+///
+/// Returns the largest absolute difference over all sites between the
+/// truncated interaction and the complete lattice sum, or an error if
+/// the lattice boundary is not periodic.
+pub fn get_truncation_error(dip: &DipolarSystem, tolerance: f64) -> Result<f64> {
+    let truncated = convolve_dd_kernel(dip, &get_dd_kernel(dip)?)?;
+    let full = convolve_dd_kernel(dip, &DdKernel::new(get_dd_kernel_lattice_sum(dip, tolerance), &dip.latt)?)?;
+
+    Ok((full - truncated).amax())
+}
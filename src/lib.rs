@@ -1,23 +1,54 @@
+#[cfg(test)]
 #[macro_use]
 extern crate approx; // For the macro assert_relative_eq!
 extern crate nalgebra as na;
 
 pub mod lattice;
+pub mod lattice_sum;
 pub mod dipolar;
+pub mod diagram;
+pub mod disorder;
+pub mod energy;
+pub mod error;
+pub mod fft;
+pub mod gutzwiller;
+pub mod momentum;
+pub mod phase;
+pub mod scaling;
+pub mod search;
+pub mod sparse;
 pub mod util;
 
 #[cfg(test)]
 mod tests {
-    use crate::{lattice::get_checkerboard,
-                dipolar::{get_dd_int_site, generate_mat_m, generate_dd_int_mat, get_tunneling, get_mu_inequality},
-                util::linspace};
-    use na::Vector3;
+    use crate::{lattice::{fill_pattern, get_checkerboard, get_filled, get_harmonic_potential, get_horizontal_stripe,
+                          get_next_lobe},
+                momentum::{find_unit_cell, get_tunneling_momentum},
+                sparse::{generate_mat_m_sparse, get_tunneling_sparse},
+                lattice_sum::{generate_dd_int_mat_lattice_sum, get_truncation_error,
+                              get_dd_kernel_cutoff, get_dd_kernel_lattice_sum},
+                dipolar::{get_dd_int_site, get_dd_offsets, get_particle_e, get_hole_e, generate_mat_m, generate_dd_int_mat, get_tunneling, get_mu_inequality,
+                          get_tunneling_bisect, get_tunneling_eigen, get_max_tunneling, get_m_coeff, Hopping, Solver, TunnelingResult, solve_tunneling,
+                          Pattern, Potential, SweepParams, simulation_sweep,
+                          get_dd_kernel, generate_dd_int_mat_fft, convolve_dd_kernel, get_dd_field_fft, DdKernel},
+                disorder::{Disorder, apply_disorder},
+                diagram::{DiagramParams, get_candidate_patterns, get_phase_diagram},
+                gutzwiller::{GutzwillerState, solve_gutzwiller},
+                phase::{DensityOrder, Phase, classify_density, get_phase_map},
+                scaling::{fit_finite_size, get_lobe_tip},
+                search::{AnnealingSchedule, EnumerationParams, anneal_occupation, find_ground_state,
+                         enumerate_patterns, get_ground_state_ranges},
+                energy::{get_energy, get_move_energy, get_site_energies, set_site_occupation},
+                error::Error,
+                util::{Config, get_sweep_axis, linspace, load_matrix_json, parallel_map, parse_config,
+                       parse_potential_str, parse_solver_str, save_json}};
+    use na::{DMatrix, DVector, Vector3};
     use std::f64::consts::PI;
 
 
     use super::*;
     use dipolar::{get_dd_int, DipolarSystem};
-    use lattice::{LattPos, PeriodicLattice, SpinIdx};
+    use lattice::{Boundary, Geometry, LattPos, PeriodicLattice, SpinIdx, Stacking};
 
     #[test]
     fn dd_repulsive_test() {
@@ -42,29 +73,37 @@ mod tests {
 
     #[test]
     fn periodic_idx_pos_test() {
-        let system = PeriodicLattice::new(4);
+        let system = PeriodicLattice::new(4).unwrap();
         let idx = 5;
-        assert_eq!(1, system.get_idx_periodic(idx));
+        assert_eq!(1, system.get_x_periodic(idx));
     }
 
     #[test]
     fn periodic_idx_neg_test() {
-        let system = PeriodicLattice::new(4);
+        let system = PeriodicLattice::new(4).unwrap();
         let idx = -1;
-        assert_eq!(3, system.get_idx_periodic(idx));
+        assert_eq!(3, system.get_x_periodic(idx));
+    }
+
+    #[test]
+    fn periodic_idx_rect_test() {
+        let system = PeriodicLattice::new_rect(4, 3).unwrap();
+        assert_eq!(1, system.get_x_periodic(5));
+        assert_eq!(2, system.get_y_periodic(5));
+        assert_eq!(2, system.get_y_periodic(-1));
     }
 
     #[test]
-    #[should_panic]
-    fn latt_pos_panic_test() {
-        let system = PeriodicLattice::new(4);
-        let _ = LattPos::new(6, 5, &system);
+    fn latt_pos_err_test() {
+        let system = PeriodicLattice::new(4).unwrap();
+        assert!(matches!(LattPos::new(6, 5, &system), Err(Error::Position { x: 6, y: 5 })));
+        assert!(matches!(SpinIdx::new(16, &system), Err(Error::SpinIdx { idx: 16, no_sites: 16 })));
     }
 
     #[test]
     fn pos_to_spin() {
-        let system = PeriodicLattice::new(4);
-        let pos = LattPos::new(3, 1, &system);
+        let system = PeriodicLattice::new(4).unwrap();
+        let pos = LattPos::new(3, 1, &system).unwrap();
         let sp = SpinIdx::from(pos);
 
         assert_eq!(sp.idx, 7);
@@ -72,17 +111,557 @@ mod tests {
 
     #[test]
     fn spin_to_pos() {
-        let system = PeriodicLattice::new(4);
-        let sp = SpinIdx::new(7, &system);
+        let system = PeriodicLattice::new(4).unwrap();
+        let sp = SpinIdx::new(7, &system).unwrap();
         let pos = LattPos::from(sp);
 
         assert_eq!(pos.x, 3);
         assert_eq!(pos.y, 1);
     }
 
+    #[test]
+    fn spin_pos_rect_test() {
+        let system = PeriodicLattice::new_rect(2, 5).unwrap();
+        let pos = LattPos::new(1, 4, &system).unwrap();
+        let sp = SpinIdx::from(pos);
+        assert_eq!(sp.idx, 9);
+
+        let pos = LattPos::from(SpinIdx::new(5, &system).unwrap());
+        assert_eq!((pos.x, pos.y), (1, 2));
+    }
+
+    #[test]
+    fn latt_pos_rect_err_test() {
+        let system = PeriodicLattice::new_rect(2, 5).unwrap();
+        assert!(LattPos::new(2, 4, &system).is_err());
+    }
+
+    #[test]
+    fn rect_system_test() {
+        let mut dip_system = DipolarSystem::new_rect(PI/2., 0., 20., 1, 4, 6).unwrap();
+        dip_system.update_occupation(get_horizontal_stripe(&dip_system.latt)).unwrap();
+        assert_eq!(dip_system.occupation.shape(), (6, 4));
+
+        generate_dd_int_mat(&mut dip_system);
+        let direct = dip_system.dd_mat.clone();
+
+        let kernel = get_dd_kernel(&dip_system).unwrap();
+        generate_dd_int_mat_fft(&mut dip_system, &kernel).unwrap();
+        assert_relative_eq!(dip_system.dd_mat, direct, epsilon = 1e-10);
+
+        let (lower, upper) = get_mu_inequality(&dip_system);
+        let mu = 0.5*(lower + upper);
+
+        let dense = get_tunneling_eigen(mu, &dip_system).unwrap();
+        let momentum = get_tunneling_momentum(mu, &dip_system, None).unwrap().unwrap();
+        let sparse = get_tunneling_sparse(mu, &dip_system, 1e-10, 200).tunneling().unwrap();
+        let bisect = get_tunneling_bisect(mu, &dip_system, 4., 1e-2, 1e-9);
+
+        assert_eq!(find_unit_cell(&dip_system), (1, 2));
+        assert_relative_eq!(momentum, dense, epsilon = 1e-10);
+        assert_relative_eq!(sparse, dense, epsilon = 1e-8);
+        assert_relative_eq!(bisect.tunneling().unwrap(), dense, epsilon = 1e-8);
+    }
+
+    #[test]
+    fn geometry_neighbours_test() {
+        for (geometry, no_neighbours) in [(Geometry::Square, 4), (Geometry::Triangular, 6),
+                                          (Geometry::Honeycomb, 3), (Geometry::Kagome, 4)] {
+            let latt = PeriodicLattice::new_geometry(geometry, 4, 4).unwrap();
+
+            for x in 0..latt.ncols() {
+                let neighbours = latt.get_neighbours(x, 1);
+                assert_eq!(neighbours.len(), no_neighbours);
+
+                // neighbours are mutual
+                for (x_n, y_n) in neighbours {
+                    assert!(latt.get_neighbours(x_n, y_n).contains(&(x, 1)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn geometry_dd_int_test() {
+        for (geometry, no_neighbours) in [(Geometry::Triangular, 6.), (Geometry::Honeycomb, 3.),
+                                          (Geometry::Kagome, 4.)] {
+            let latt = PeriodicLattice::new_geometry(geometry, 6, 6).unwrap();
+            let mut dip_system = DipolarSystem::from_lattice(0., 0., 20., 1, latt);
+            dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+            generate_dd_int_mat(&mut dip_system);
+
+            assert_relative_eq!(dip_system.dd_mat.max(), no_neighbours, epsilon = 1e-12);
+            assert_relative_eq!(dip_system.dd_mat.min(), no_neighbours, epsilon = 1e-12);
+
+            // tilted dipoles and a longer range with the FFT path
+            let latt = PeriodicLattice::new_geometry(geometry, 5, 4).unwrap();
+            let mut dip_system = DipolarSystem::from_lattice(PI/3., PI/5., 20., 3, latt);
+            dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+
+            generate_dd_int_mat(&mut dip_system);
+            let direct = dip_system.dd_mat.clone();
+
+            let kernel = get_dd_kernel(&dip_system).unwrap();
+            generate_dd_int_mat_fft(&mut dip_system, &kernel).unwrap();
+            assert_relative_eq!(dip_system.dd_mat, direct, epsilon = 1e-10);
+        }
+    }
+
+    #[test]
+    fn geometry_tunneling_test() {
+        let latt = PeriodicLattice::new_geometry(Geometry::Honeycomb, 4, 6).unwrap();
+        let mut dip_system = DipolarSystem::from_lattice(0., 0., 20., 1, latt);
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        // uniform lattice with 3 neighbours, n = 1 and dd = 3
+        let mu = 10.;
+        let particle_e = -mu + 20. + 3.;
+        let hole_e = mu - 3.;
+        let expected = 1./(3.*(2./particle_e + 1./hole_e));
+
+        assert_eq!(find_unit_cell(&dip_system), (2, 1));
+        assert_relative_eq!(get_tunneling_eigen(mu, &dip_system).unwrap(), expected, epsilon = 1e-10);
+        assert_relative_eq!(get_tunneling_momentum(mu, &dip_system, None).unwrap().unwrap(), expected, epsilon = 1e-10);
+        assert_relative_eq!(get_tunneling_sparse(mu, &dip_system, 1e-10, 200).tunneling().unwrap(), expected, epsilon = 1e-8);
+
+        let latt = PeriodicLattice::new_geometry(Geometry::Kagome, 4, 4).unwrap();
+        let mut dip_system = DipolarSystem::from_lattice(PI/2., 0., 20., 2, latt);
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        let kernel = get_dd_kernel(&dip_system).unwrap();
+        generate_dd_int_mat_fft(&mut dip_system, &kernel).unwrap();
+
+        let (lower, upper) = get_mu_inequality(&dip_system);
+        let mu = 0.5*(lower + upper);
+        let dense = get_tunneling_eigen(mu, &dip_system).unwrap();
+
+        assert_relative_eq!(get_tunneling_momentum(mu, &dip_system, None).unwrap().unwrap(), dense, epsilon = 1e-10);
+        assert_relative_eq!(get_tunneling_bisect(mu, &dip_system, 4., 1e-2, 1e-9).tunneling().unwrap(),
+                            dense, epsilon = 1e-8);
+    }
+
+    #[test]
+    fn boundary_neighbours_test() {
+        let latt = PeriodicLattice::new(4).unwrap().with_boundary(Boundary::Open).unwrap();
+        assert_eq!(latt.get_neighbours(0, 0).len(), 2);
+        assert_eq!(latt.get_neighbours(1, 0).len(), 3);
+        assert_eq!(latt.get_neighbours(1, 1).len(), 4);
+
+        let latt = PeriodicLattice::new(4).unwrap().with_boundary(Boundary::CylinderX).unwrap();
+        assert_eq!(latt.get_neighbours(0, 0).len(), 3);
+        assert!(latt.get_neighbours(0, 0).contains(&(3, 0)));
+
+        let latt = PeriodicLattice::new(4).unwrap().with_boundary(Boundary::CylinderY).unwrap();
+        assert_eq!(latt.get_neighbours(0, 0).len(), 3);
+        assert!(latt.get_neighbours(0, 0).contains(&(0, 3)));
+
+        // twisted boundaries keep the periodic neighbours and flip the sign of wrapped hops
+        let latt = PeriodicLattice::new(4).unwrap()
+            .with_boundary(Boundary::Twisted { twist_x: true, twist_y: false }).unwrap();
+        assert_eq!(latt.get_neighbours(0, 0).len(), 4);
+        assert!(latt.get_neighbours(0, 0).contains(&(3, 0)));
+        assert_eq!(latt.get_twist_sign(0, 0, -1, 0), -1.);
+        assert_eq!(latt.get_twist_sign(3, 0, 1, 0), -1.);
+        assert_eq!(latt.get_twist_sign(1, 0, -1, 0), 1.);
+        assert_eq!(latt.get_twist_sign(0, 0, 0, -1), 1.);
+
+        // the direct and the wrapped hop would reach the same site
+        assert!(matches!(PeriodicLattice::new_rect(4, 2).unwrap()
+                         .with_boundary(Boundary::Twisted { twist_x: false, twist_y: true }),
+                         Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn boundary_dd_int_test() {
+        let latt = PeriodicLattice::new(4).unwrap().with_boundary(Boundary::Open).unwrap();
+        let mut dip_system = DipolarSystem::from_lattice(0., 0., 20., 1, latt);
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        assert_relative_eq!(dip_system.dd_mat[(0, 0)], 2.);
+        assert_relative_eq!(dip_system.dd_mat[(0, 1)], 3.);
+        assert_relative_eq!(dip_system.dd_mat[(1, 1)], 4.);
+
+        // twisted boundaries only change the tunneling, the d-d interaction stays periodic
+        let latt = PeriodicLattice::new(4).unwrap()
+            .with_boundary(Boundary::Twisted { twist_x: true, twist_y: true }).unwrap();
+        let mut dip_system = DipolarSystem::from_lattice(0., 0., 20., 2, latt);
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let mut periodic = DipolarSystem::new(0., 0., 20., 2, 4).unwrap();
+        periodic.update_occupation(get_checkerboard(&periodic.latt)).unwrap();
+        generate_dd_int_mat(&mut periodic);
+        assert_relative_eq!(dip_system.dd_mat, periodic.dd_mat, epsilon = 1e-12);
+
+        let kernel = get_dd_kernel(&dip_system).unwrap();
+        generate_dd_int_mat_fft(&mut dip_system, &kernel).unwrap();
+        assert_relative_eq!(dip_system.dd_mat, periodic.dd_mat, epsilon = 1e-12);
+    }
+
+    #[test]
+    fn boundary_tunneling_test() {
+        let latt = PeriodicLattice::new_rect(4, 6).unwrap().with_boundary(Boundary::Open).unwrap();
+        let mut dip_system = DipolarSystem::from_lattice(0., 0., 20., 1, latt);
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let mu = 1.;
+        let dense = get_tunneling_eigen(mu, &dip_system).unwrap();
+
+        assert_relative_eq!(get_tunneling_sparse(mu, &dip_system, 1e-10, 200).tunneling().unwrap(), dense, epsilon = 1e-8);
+        assert_relative_eq!(get_tunneling_bisect(mu, &dip_system, 4., 1e-2, 1e-9).tunneling().unwrap(),
+                            dense, epsilon = 1e-8);
+
+        // the momentum solver falls back to the eigen solver
+        assert!(matches!(get_dd_kernel(&dip_system), Err(Error::Unsupported(_))));
+        assert!(matches!(get_tunneling_momentum(mu, &dip_system, None), Err(Error::Unsupported(_))));
+        assert_relative_eq!(solve_tunneling(mu, &dip_system, &Solver::Momentum), dense, epsilon = 1e-12);
+
+        // fewer bonds than the periodic lattice
+        let mut periodic = DipolarSystem::new_rect(0., 0., 20., 1, 4, 6).unwrap();
+        periodic.update_occupation(get_checkerboard(&periodic.latt)).unwrap();
+        generate_dd_int_mat(&mut periodic);
+        assert!(dense > get_tunneling_eigen(mu, &periodic).unwrap());
+    }
+
+    #[test]
+    fn twisted_tunneling_test() {
+        let size = 4;
+        let latt = PeriodicLattice::new(size).unwrap()
+            .with_boundary(Boundary::Twisted { twist_x: true, twist_y: false }).unwrap();
+        let mut dip_system = DipolarSystem::from_lattice(0., 0., 20., 1, latt);
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        // wrapped bonds along x carry a minus sign
+        let m_mat = generate_mat_m(1., 0.1, &dip_system);
+        let coeff = get_m_coeff(0, 0, 1., &dip_system);
+        assert_relative_eq!(m_mat[(0, 1)], 0.1*coeff);
+        assert_relative_eq!(m_mat[(0, size - 1)], -0.1*coeff);
+        assert_relative_eq!(m_mat[(0, size)], 0.1*coeff);
+
+        // the lowest momentum along x is shifted to pi/L
+        let mu = 10.;
+        let coeff = get_m_coeff(0, 0, mu, &dip_system);
+        let expected = 1./(coeff.abs()*(2. + 2.*(PI/size as f64).cos()));
+
+        assert_relative_eq!(get_tunneling_eigen(mu, &dip_system).unwrap(), expected, epsilon = 1e-10);
+        assert_relative_eq!(get_tunneling_sparse(mu, &dip_system, 1e-12, 200).tunneling().unwrap(), expected, epsilon = 1e-8);
+        // the momenta +-pi/L are degenerate, so det(M) touches zero without changing sign
+        assert_relative_eq!(generate_mat_m(mu, expected, &dip_system).determinant(), 0., epsilon = 1e-10);
+        assert!(generate_mat_m(mu, 1.01*expected, &dip_system).determinant() > 0.);
+
+        // the bisection does not rely on the sign change
+        assert_relative_eq!(get_tunneling_bisect(mu, &dip_system, 4., 1e-2, 1e-10).tunneling().unwrap(),
+                            expected, epsilon = 1e-8);
+        assert_relative_eq!(solve_tunneling(mu, &dip_system, &Solver::Bisection { bracket_step: 1e-2, tolerance: 1e-6 }),
+                            expected, epsilon = 1e-6);
+        // the scan stops where |det(M)| first drops below its threshold, before the double zero
+        let scan = solve_tunneling(mu, &dip_system, &Solver::Scan);
+        assert!(0.9*expected < scan && scan < expected);
+
+        // the momentum solver falls back to the eigen solver
+        assert!(matches!(get_tunneling_momentum(mu, &dip_system, None), Err(Error::Unsupported(_))));
+        assert_relative_eq!(solve_tunneling(mu, &dip_system, &Solver::Momentum), expected, epsilon = 1e-10);
+    }
+
+    #[test]
+    fn stacking_neighbours_test() {
+        for (latt, no_neighbours) in [(PeriodicLattice::new(4).unwrap().with_stacking(Stacking::Open(2, 0.5)).unwrap(), 5),
+                                      (PeriodicLattice::new(4).unwrap().with_stacking(Stacking::Open(3, 1.)).unwrap(), 6),
+                                      (PeriodicLattice::new_cubic(4).unwrap(), 6)] {
+            // sites in the middle layer
+            let x = latt.n_cell_sites() + 1;
+            assert_eq!(latt.get_neighbours(x, 1).len(), no_neighbours);
+
+            for x in 0..latt.ncols() {
+                for (x_n, y_n) in latt.get_neighbours(x, 1) {
+                    assert!(latt.get_neighbours(x_n, y_n).contains(&(x, 1)));
+                }
+            }
+        }
+
+        let latt = PeriodicLattice::new(4).unwrap().with_stacking(Stacking::Open(3, 1.)).unwrap();
+        assert_eq!(latt.get_neighbours(0, 0).len(), 5);
+        assert_eq!(latt.get_layer_col(5), 1);
+    }
+
+    #[test]
+    fn stacking_dd_int_test() {
+        // dipoles along z attract head to tail between layers
+        let latt = PeriodicLattice::new(4).unwrap().with_stacking(Stacking::Open(2, 1.)).unwrap();
+        let mut dip_system = DipolarSystem::from_lattice(0., 0., 20., 1, latt);
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        assert_relative_eq!(dip_system.dd_mat.max(), 2., epsilon = 1e-12);
+        assert_relative_eq!(dip_system.dd_mat.min(), 2., epsilon = 1e-12);
+
+        let mut dip_system = DipolarSystem::from_lattice(0., 0., 20., 1, PeriodicLattice::new_cubic(4).unwrap());
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        assert_relative_eq!(dip_system.dd_mat.amax(), 0., epsilon = 1e-12);
+
+        for stacking in [Stacking::Open(2, 0.7), Stacking::Periodic(3, 1.2)] {
+            let latt = PeriodicLattice::new_geometry(Geometry::Honeycomb, 4, 5).unwrap().with_stacking(stacking).unwrap();
+            let mut dip_system = DipolarSystem::from_lattice(PI/3., PI/5., 20., 3, latt);
+            dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+
+            generate_dd_int_mat(&mut dip_system);
+            let direct = dip_system.dd_mat.clone();
+
+            let kernel = get_dd_kernel(&dip_system).unwrap();
+            generate_dd_int_mat_fft(&mut dip_system, &kernel).unwrap();
+            assert_relative_eq!(dip_system.dd_mat, direct, epsilon = 1e-10);
+        }
+    }
+
+    #[test]
+    fn stacking_tunneling_test() {
+        let latt = PeriodicLattice::new_rect(4, 6).unwrap().with_stacking(Stacking::Open(2, 1.)).unwrap();
+        let mut dip_system = DipolarSystem::from_lattice(0., 0., 20., 1, latt);
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        // uniform lattice with 5 neighbours, n = 1 and dd = 2
+        let mu = 10.;
+        let particle_e = -mu + 20. + 2.;
+        let hole_e = mu - 2.;
+        let expected = 1./(5.*(2./particle_e + 1./hole_e));
+
+        assert_eq!(find_unit_cell(&dip_system), (2, 1));
+        assert_relative_eq!(get_tunneling_eigen(mu, &dip_system).unwrap(), expected, epsilon = 1e-10);
+        assert_relative_eq!(get_tunneling_momentum(mu, &dip_system, None).unwrap().unwrap(), expected, epsilon = 1e-10);
+        assert_relative_eq!(get_tunneling_sparse(mu, &dip_system, 1e-10, 200).tunneling().unwrap(), expected, epsilon = 1e-8);
+    }
+
+    #[test]
+    fn stacking_two_layers_test() {
+        // both directions along z reach the other layer, which is one bond
+        let latt = PeriodicLattice::new(4).unwrap().with_stacking(Stacking::Periodic(2, 1.)).unwrap();
+        assert_eq!(latt.neighbour_offsets()[0].len(), 5);
+        let latt = PeriodicLattice::new(2).unwrap().with_stacking(Stacking::Cubic).unwrap();
+        assert_eq!(latt.neighbour_offsets()[0].len(), 5);
+
+        let latt = PeriodicLattice::new(4).unwrap().with_stacking(Stacking::Periodic(2, 1.)).unwrap();
+        let mut dip_system = DipolarSystem::from_lattice(0., 0., 20., 1, latt);
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        dip_system.hopping = Hopping::new(1., 1., 0.5, 0.);
+
+        let hoppings = dip_system.get_hoppings(0, 0);
+        assert_eq!(hoppings.len(), 5);
+        assert!(hoppings.contains(&(1, 0, 0.5)));
+
+        let mu = 10.;
+        let dense = get_tunneling_eigen(mu, &dip_system).unwrap();
+        let coeff = get_m_coeff(0, 0, mu, &dip_system);
+        assert_relative_eq!(dense, -1./(coeff*4.5), epsilon = 1e-10);
+        assert_relative_eq!(get_tunneling_momentum(mu, &dip_system, None).unwrap().unwrap(), dense, epsilon = 1e-10);
+        assert_relative_eq!(get_tunneling_sparse(mu, &dip_system, 1e-10, 200).tunneling().unwrap(), dense, epsilon = 1e-8);
+        assert_relative_eq!(get_tunneling_bisect(mu, &dip_system, 4., 1e-2, 1e-10).tunneling().unwrap(),
+                            dense, epsilon = 1e-8);
+    }
+
+    #[test]
+    fn gutzwiller_atomic_limit_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+
+        let initial = GutzwillerState::new(&dip_system, 3, 0.1);
+        let state = solve_gutzwiller(1., 0., &dip_system, initial, 1e-12, 100);
+
+        // checkerboard without d-d interaction at the occupied sites
+        assert!(state.converged);
+        assert_relative_eq!(state.max_order_param(), 0., epsilon = 1e-11);
+        assert_relative_eq!(state.density, dip_system.occupation.map(|n| n as f64), epsilon = 1e-12);
+        assert_relative_eq!(state.energy, -0.5, epsilon = 1e-12);
+    }
+
+    #[test]
+    fn gutzwiller_mott_boundary_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let mu = 10.;
+        let t_c = get_tunneling_eigen(mu, &dip_system).unwrap();
+
+        let initial = GutzwillerState::new(&dip_system, 3, 0.1);
+        let insulator = solve_gutzwiller(mu, 0.9*t_c, &dip_system, initial.clone(), 1e-10, 5000);
+        assert!(insulator.converged);
+        assert!(insulator.max_order_param() < 1e-6);
+        assert_relative_eq!(insulator.mean_density(), 1., epsilon = 1e-6);
+
+        let superfluid = solve_gutzwiller(mu, 1.1*t_c, &dip_system, initial, 1e-10, 5000);
+        assert!(superfluid.converged);
+        assert!(superfluid.max_order_param() > 1e-2);
+        assert!(superfluid.energy < insulator.energy);
+    }
+
+    #[test]
+    fn classify_density_test() {
+        let latt = PeriodicLattice::new(4).unwrap();
+        let classify = |occupation: DMatrix<u8>| classify_density(&(occupation.map(|n| 0.3 + 0.5*n as f64)),
+                                                                  &latt, 1e-6);
+
+        assert_eq!(classify(get_filled(&latt)), DensityOrder::Uniform);
+        assert_eq!(classify(get_checkerboard(&latt)), DensityOrder::Checkerboard);
+        assert_eq!(classify(get_horizontal_stripe(&latt)), DensityOrder::HStripe);
+        assert_eq!(classify(get_horizontal_stripe(&latt).transpose()), DensityOrder::VStripe);
+
+        let mut single = DMatrix::zeros(4, 4);
+        single[(1, 2)] = 1;
+        assert_eq!(classify(single), DensityOrder::Other);
+
+        // stacked layers repeat the pattern
+        let latt = PeriodicLattice::new(4).unwrap().with_stacking(Stacking::Open(2, 1.)).unwrap();
+        let density = get_checkerboard(&latt).map(|n| n as f64);
+        assert_eq!(classify_density(&density, &latt, 1e-6), DensityOrder::Checkerboard);
+    }
+
+    #[test]
+    fn phase_map_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+
+        let mu_vals = DVector::from_vec(vec![-1., 1.]);
+        let t_vals = DVector::from_vec(vec![0., 2.]);
+        let phases = get_phase_map(&mu_vals, &t_vals, &dip_system, 1, 2);
+
+        assert_eq!(phases.shape(), (2, 2));
+        assert_eq!(phases[(0, 0)], Phase::Vacuum);
+        assert_eq!(phases[(0, 1)], Phase::DensityWave(DensityOrder::Checkerboard));
+        assert_eq!(phases[(1, 1)], Phase::Superfluid);
+        assert_eq!(phases[(0, 0)].label(), "VAC");
+        assert_eq!(phases[(0, 1)].label(), "DW_cb");
+
+        // the map does not depend on the occupation it starts from
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        assert_eq!(get_phase_map(&mu_vals, &t_vals, &dip_system, 1, 2), phases);
+    }
+
+    #[test]
+    fn energy_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        let get_atomic_energy = |mu: f64, dip: &DipolarSystem| get_energy(mu, dip).total()/16.;
+
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        assert_relative_eq!(get_atomic_energy(1., &dip_system), 1., epsilon = 1e-12);
+
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        assert_relative_eq!(get_atomic_energy(1., &dip_system), -0.5, epsilon = 1e-12);
+
+        dip_system.update_occupation(get_filled(&dip_system.latt)*2).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        let energy = get_energy(1., &dip_system);
+        assert_relative_eq!(energy.onsite, 16.*20., epsilon = 1e-12);
+        assert_relative_eq!(energy.dipolar, 16.*8., epsilon = 1e-12);
+        assert_relative_eq!(energy.chemical, -16.*2., epsilon = 1e-12);
+        assert_relative_eq!(get_site_energies(1., &dip_system).sum(), energy.total(), epsilon = 1e-10);
+    }
+
+    #[test]
+    fn move_energy_test() {
+        let mut dip_system = DipolarSystem::new(PI/3., PI/5., 20., 3, 5).unwrap();
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        let dd_offsets = get_dd_offsets(&dip_system);
+        let mu = 3.;
+
+        assert_relative_eq!(get_move_energy(1, 2, 2, mu, &dip_system),
+                            get_particle_e(1, 2, mu, &dip_system), epsilon = 1e-12);
+        assert_relative_eq!(get_move_energy(1, 2, 0, mu, &dip_system),
+                            get_hole_e(1, 2, mu, &dip_system), epsilon = 1e-12);
+
+        // local updates agree with recomputing everything
+        for (x, y, n_new) in [(1, 2, 2), (0, 0, 1), (4, 3, 3), (1, 2, 0)] {
+            let before = get_energy(mu, &dip_system).total();
+            let change = get_move_energy(x, y, n_new, mu, &dip_system);
+            set_site_occupation(x, y, n_new, &mut dip_system, &dd_offsets);
+
+            let local = dip_system.dd_mat.clone();
+            generate_dd_int_mat(&mut dip_system);
+
+            assert_relative_eq!(local, dip_system.dd_mat, epsilon = 1e-12);
+            assert_relative_eq!(get_energy(mu, &dip_system).total(), before + change, epsilon = 1e-10);
+        }
+    }
+
+    #[test]
+    fn anneal_occupation_test() {
+        let dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        let schedule = AnnealingSchedule::new(2., 1e-2, 200);
+
+        let result = find_ground_state(1., &dip_system, 2, &schedule, 0, 2);
+        assert_eq!(classify_density(&result.occupation.map(|n| n as f64), &dip_system.latt, 1e-6),
+                   DensityOrder::Checkerboard);
+        assert_eq!(result.occupation.sum(), 8);
+
+        let mut check = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        check.update_occupation(result.occupation.clone()).unwrap();
+        generate_dd_int_mat(&mut check);
+        assert_relative_eq!(result.energy, get_energy(1., &check).total()/16., epsilon = 1e-12);
+
+        // empty below mu = 0, filled far above the d-d interaction
+        assert_eq!(find_ground_state(-1., &dip_system, 2, &schedule, 0, 1).occupation.sum(), 0);
+        assert_eq!(find_ground_state(10., &dip_system, 2, &schedule, 0, 1).occupation,
+                   get_filled(&dip_system.latt));
+
+        // reproducible with the same seed
+        let first = anneal_occupation(1., &dip_system, 2, &AnnealingSchedule::new(2., 1., 5), 7);
+        let second = anneal_occupation(1., &dip_system, 2, &AnnealingSchedule::new(2., 1., 5), 7);
+        assert_eq!(first.occupation, second.occupation);
+    }
+
+    #[test]
+    fn enumerate_patterns_test() {
+        let dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        let patterns = enumerate_patterns(&dip_system, 4, 1).unwrap();
+
+        // checkerboard, stripes of width 1 and width 2, diagonal stripes of width 2
+        // and the checkerboard of 2x1 blocks, rotations are equivalent
+        let half_filled: Vec<_> = patterns.iter().filter(|p| p.density == 0.5).collect();
+        assert_eq!(half_filled.len(), 5);
+
+        // the diagonal stripes need the sheared unit cell
+        let diagonal = half_filled.iter().find(|p| p.unit_cell == (4, 1) && p.shear == 1).unwrap();
+        for y in 0..4 {
+            for x in 0..4 {
+                assert_eq!(diagonal.occupation[((y + 1)%4, (x + 1)%4)], diagonal.occupation[(y, x)]);
+            }
+        }
+        assert_ne!(diagonal.occupation[(0, 0)], diagonal.occupation[(0, 2)]);
+
+        let ranges = get_ground_state_ranges(&patterns, (-1., 25.));
+        let orders: Vec<_> = ranges.iter()
+            .map(|&(idx, _)| classify_density(&patterns[idx].occupation.map(|n| n as f64),
+                                              &dip_system.latt, 1e-6))
+            .collect();
+
+        assert_eq!(orders, vec![DensityOrder::Uniform, DensityOrder::Checkerboard, DensityOrder::Uniform]);
+        assert_relative_eq!(ranges[0].1.1, 0., epsilon = 1e-12);
+        assert_relative_eq!(ranges[1].1.0, 0., epsilon = 1e-12);
+        assert_relative_eq!(ranges[1].1.1, 4., epsilon = 1e-12);
+        assert_eq!(patterns[ranges[2].0].density, 1.);
+
+        let cb = &patterns[ranges[1].0];
+        assert_eq!((cb.unit_cell, cb.shear), ((2, 1), 1));
+        assert_relative_eq!(cb.stable_range.0, 0., epsilon = 1e-12);
+        assert_relative_eq!(cb.stable_range.1, 4., epsilon = 1e-12);
+
+        // 4^16 occupations of the 4x4 cell, and 256^16 overflows
+        assert!(matches!(enumerate_patterns(&dip_system, 16, 3), Err(Error::Config(_))));
+        assert!(matches!(enumerate_patterns(&dip_system, 16, 255), Err(Error::Config(_))));
+    }
+
     #[test]
     fn dipole_vec_test() {
-        let dip_system = DipolarSystem::new(PI/2., 0., 0., 2, 4);
+        let dip_system = DipolarSystem::new(PI/2., 0., 0., 2, 4).unwrap();
 
         let dip_vec = dip_system.get_dipole_vec();
 
@@ -91,19 +670,66 @@ mod tests {
 
     #[test]
     fn dipole_int_test() {
-        let mut dip_system = DipolarSystem::new(0., 0., 0., 1, 4);
+        let mut dip_system = DipolarSystem::new(0., 0., 0., 1, 4).unwrap();
 
-        dip_system.update_occupation(get_checkerboard(&dip_system.latt));
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
 
         let interaction = get_dd_int_site(0, 0, &dip_system);
         assert_relative_eq!(interaction, 4.);  
     }
 
+    #[test]
+    fn magic_angle_test() {
+        // the interaction along x vanishes at sin(theta)^2 = 1/3
+        let theta = (1./3f64.sqrt()).asin();
+        assert_relative_eq!(theta/PI, 0.1959, epsilon = 1e-4);
+
+        let dip_system = DipolarSystem::new(theta, 0., 0., 1, 4).unwrap();
+        assert_relative_eq!(get_dd_int(Vector3::new(1., 0., 0.), dip_system.get_dipole_vec()), 0., epsilon = 1e-12);
+        assert_relative_eq!(get_dd_int(Vector3::new(0., 1., 0.), dip_system.get_dipole_vec()), 1., epsilon = 1e-12);
+    }
+
+    #[test]
+    fn dd_int_mat_fft_test() {
+        // int_range larger than the system wraps around multiple times
+        for (int_range, system_size) in [(1, 4), (3, 6), (5, 5), (9, 4)] {
+            let mut dip_system = DipolarSystem::new(PI/3., PI/5., 20., int_range, system_size).unwrap();
+            let occupation = DMatrix::from_fn(system_size, system_size,
+                                              |row, col| ((row*row + 3*col)%3 == 0) as u8);
+            dip_system.update_occupation(occupation).unwrap();
+
+            generate_dd_int_mat(&mut dip_system);
+            let direct = dip_system.dd_mat.clone();
+
+            let kernel = get_dd_kernel(&dip_system).unwrap();
+            generate_dd_int_mat_fft(&mut dip_system, &kernel).unwrap();
+
+            assert_relative_eq!(dip_system.dd_mat, direct, epsilon = 1e-10);
+        }
+    }
+
+    #[test]
+    fn dd_lattice_sum_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat_lattice_sum(&mut dip_system, 1e-6).unwrap();
+
+        // sum of 1/|d|^3 over the square lattice
+        let lattice_sum = 9.0336217;
+        assert_relative_eq!(dip_system.dd_mat[(0, 0)], lattice_sum, epsilon = 1e-5);
+        assert_relative_eq!(get_truncation_error(&dip_system, 1e-6).unwrap(), lattice_sum - 4., epsilon = 1e-5);
+
+        // tilted dipoles converge to the same kernel as a large cutoff
+        let dip_system = DipolarSystem::new(PI/3., PI/7., 20., 1, 6).unwrap();
+        let kernel = get_dd_kernel_lattice_sum(&dip_system, 1e-10);
+        assert_relative_eq!(kernel[0], get_dd_kernel_cutoff(&dip_system, 100.)[0], epsilon = 1e-9);
+    }
+
     #[test]
     fn m_matrix_det_test() {
-        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 2);
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 2).unwrap();
 
-        dip_system.update_occupation(get_checkerboard(&dip_system.latt));
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
 
         generate_dd_int_mat(&mut dip_system);
         
@@ -117,8 +743,8 @@ mod tests {
 
     #[test]
     fn get_tunneling_test() {
-        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 2);
-        dip_system.update_occupation(get_checkerboard(&dip_system.latt));
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 2).unwrap();
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
         generate_dd_int_mat(&mut dip_system);
 
         let mu = 1.;
@@ -129,10 +755,490 @@ mod tests {
 
     }
 
+    #[test]
+    fn get_tunneling_bisect_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 2).unwrap();
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let mu = 1.;
+        let result = get_tunneling_bisect(mu, &dip_system, 1., 1e-2, 1e-9);
+
+        // analytic value for the 2x2 checkerboard
+        let expected = 1./(2.*f64::sqrt((2./19. + 1.)/3.));
+        assert_relative_eq!(result.tunneling().unwrap(), expected, epsilon = 1e-8);
+
+        if let TunnelingResult::Found { tunneling, residual, .. } = result {
+            assert!(residual.abs() < 1e-6);
+            assert_eq!(residual, generate_mat_m(mu, tunneling, &dip_system).determinant());
+        }
+    }
+
+    #[test]
+    fn get_tunneling_bisect_not_found_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 2).unwrap();
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let result = get_tunneling_bisect(1., &dip_system, 0.5, 1e-2, 1e-9);
+        assert_eq!(result, TunnelingResult::NotFound);
+
+        // steps which would never leave the bracket or never narrow it
+        assert_eq!(get_tunneling_bisect(1., &dip_system, 1., 0., 1e-9), TunnelingResult::NotFound);
+        assert_eq!(get_tunneling_bisect(1., &dip_system, 1., 1e-2, 0.), TunnelingResult::NotFound);
+        assert_eq!(get_tunneling_bisect(1., &dip_system, 1., 1e-2, 1e-300), TunnelingResult::NotFound);
+
+        for (bracket_step, tolerance) in [(0., 1e-6), (-1e-2, 1e-6), (f64::NAN, 1e-6), (1e-2, 0.), (1e-2, f64::INFINITY)] {
+            assert!(matches!(parse_solver_str("bisection", bracket_step, tolerance, 1e-10, 500), Err(Error::Config(_))));
+        }
+    }
+
+    #[test]
+    fn get_tunneling_bisect_degenerate_test() {
+        // decoupled chains along x and decoupled layers, where the
+        // largest eigenvalue of S is 4 and 2 fold degenerate
+        let chains = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        let layers = DipolarSystem::from_lattice(0., 0., 20., 1, PeriodicLattice::new(4).unwrap()
+                                                 .with_stacking(Stacking::Open(2, 1.)).unwrap());
+
+        for (mut dip_system, hopping) in [(chains, Hopping::new(1., 0., 1., 0.)), (layers, Hopping::new(1., 1., 0., 0.))] {
+            dip_system.hopping = hopping;
+            dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+            generate_dd_int_mat(&mut dip_system);
+
+            let mu = 10.;
+            let eigen = get_tunneling_eigen(mu, &dip_system).unwrap();
+
+            // det(M) touches zero at the critical tunneling without changing sign
+            assert!(generate_mat_m(mu, 1.01*eigen, &dip_system).determinant() > 0.);
+            assert_relative_eq!(get_tunneling_bisect(mu, &dip_system, 4., 1e-2, 1e-10).tunneling().unwrap(),
+                                eigen, epsilon = 1e-8);
+        }
+    }
+
+    #[test]
+    fn get_tunneling_eigen_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 2).unwrap();
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let expected = 1./(2.*f64::sqrt((2./19. + 1.)/3.));
+        assert_relative_eq!(get_tunneling_eigen(1., &dip_system).unwrap(), expected, epsilon = 1e-10);
+
+        // outside of the stability region
+        assert_eq!(get_tunneling_eigen(5., &dip_system), None);
+    }
+
+    #[test]
+    fn get_tunneling_eigen_bisect_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 2, 6).unwrap();
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let mu = 3.;
+        let eigen = get_tunneling_eigen(mu, &dip_system).unwrap();
+        let bisect = get_tunneling_bisect(mu, &dip_system, 4., 1e-2, 1e-9);
+
+        assert_relative_eq!(eigen, bisect.tunneling().unwrap(), epsilon = 1e-8);
+    }
+
+    #[test]
+    fn find_unit_cell_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 2, 6).unwrap();
+
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        assert_eq!(find_unit_cell(&dip_system), (2, 2));
+
+        dip_system.update_occupation(get_horizontal_stripe(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        assert_eq!(find_unit_cell(&dip_system), (1, 2));
+
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        assert_eq!(find_unit_cell(&dip_system), (1, 1));
+    }
+
+    #[test]
+    fn get_tunneling_momentum_test() {
+        let mut dip_system = DipolarSystem::new(PI/4., 0., 20., 2, 6).unwrap();
+        dip_system.update_occupation(get_horizontal_stripe(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let (lower, upper) = get_mu_inequality(&dip_system);
+        let mu = 0.5*(lower + upper);
+
+        let dense = get_tunneling_eigen(mu, &dip_system).unwrap();
+        let momentum = get_tunneling_momentum(mu, &dip_system, None).unwrap().unwrap();
+        // the whole lattice as a unit cell is the dense problem
+        let full_cell = get_tunneling_momentum(mu, &dip_system, Some((6, 6))).unwrap().unwrap();
+
+        assert_relative_eq!(momentum, dense, epsilon = 1e-10);
+        assert_relative_eq!(full_cell, dense, epsilon = 1e-10);
+
+        // the stripe is not periodic along y with a single row, and 4 does not divide 6
+        for unit_cell in [(1, 1), (4, 2), (0, 2)] {
+            assert!(matches!(get_tunneling_momentum(mu, &dip_system, Some(unit_cell)),
+                             Err(Error::UnitCell { .. })));
+        }
+    }
+
+    #[test]
+    fn size_two_tunneling_test() {
+        // both neighbours along x and y wrap onto the same site
+        for occupation in [get_filled(&PeriodicLattice::new(2).unwrap()), get_checkerboard(&PeriodicLattice::new(2).unwrap())] {
+            let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 2).unwrap();
+            dip_system.update_occupation(occupation).unwrap();
+            generate_dd_int_mat(&mut dip_system);
+
+            let (lower, upper) = get_mu_inequality(&dip_system);
+            let mu = 0.5*(lower + upper);
+            let eigen = get_tunneling_eigen(mu, &dip_system).unwrap();
+
+            assert_relative_eq!(get_tunneling_momentum(mu, &dip_system, None).unwrap().unwrap(), eigen, epsilon = 1e-10);
+            assert_relative_eq!(get_tunneling_sparse(mu, &dip_system, 1e-12, 100).tunneling().unwrap(), eigen, epsilon = 1e-8);
+            assert_relative_eq!(get_tunneling_bisect(mu, &dip_system, 4., 1e-2, 1e-10).tunneling().unwrap(),
+                                eigen, epsilon = 1e-8);
+            assert_eq!(dip_system.get_hoppings(0, 0).len(), 2);
+
+            // all four diagonals wrap onto the same site as well
+            dip_system.hopping = Hopping::new(1., 1., 1., 0.25);
+            let eigen = get_tunneling_eigen(mu, &dip_system).unwrap();
+
+            assert_relative_eq!(get_tunneling_momentum(mu, &dip_system, None).unwrap().unwrap(), eigen, epsilon = 1e-10);
+            assert_relative_eq!(get_tunneling_sparse(mu, &dip_system, 1e-12, 100).tunneling().unwrap(), eigen, epsilon = 1e-8);
+            assert_relative_eq!(get_tunneling_bisect(mu, &dip_system, 4., 1e-2, 1e-10).tunneling().unwrap(),
+                                eigen, epsilon = 1e-8);
+            assert_eq!(dip_system.get_hoppings(0, 0).len(), 3);
+        }
+    }
+
+    #[test]
+    fn sparse_m_matrix_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let m_sparse = generate_mat_m_sparse(1., 0.3, &dip_system);
+
+        assert_eq!(m_sparse.nnz(), 5*16);
+        assert_relative_eq!(m_sparse.to_dense(), generate_mat_m(1., 0.3, &dip_system));
+
+        // neighbours coincide for system_size 2
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 2).unwrap();
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let m_sparse = generate_mat_m_sparse(1., 0.3, &dip_system);
+        assert_relative_eq!(m_sparse.to_dense(), generate_mat_m(1., 0.3, &dip_system));
+    }
+
+    #[test]
+    fn get_tunneling_sparse_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 2, 8).unwrap();
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let mu = 3.;
+        let dense = get_tunneling_eigen(mu, &dip_system).unwrap();
+        let sparse = get_tunneling_sparse(mu, &dip_system, 1e-10, 200).tunneling().unwrap();
+
+        assert_relative_eq!(sparse, dense, epsilon = 1e-8);
+
+        // a single Lanczos step has not converged for the stripe
+        dip_system.update_occupation(get_horizontal_stripe(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        assert!(get_tunneling_eigen(mu, &dip_system).is_some());
+        assert_eq!(get_tunneling_sparse(mu, &dip_system, 1e-10, 1), TunnelingResult::NotConverged);
+        assert_eq!(solve_tunneling(mu, &dip_system, &Solver::Sparse { tolerance: 1e-10, max_iter: 1 }), 0.);
+
+        if let TunnelingResult::Found { tunneling, residual, iterations } = get_tunneling_sparse(mu, &dip_system, 1e-10, 200) {
+            assert!(residual < 1e-10/tunneling);
+            assert!(1 < iterations && iterations <= 200);
+        } else {
+            panic!("sparse solver not converged");
+        }
+
+        // mu outside the stability range of the stripe
+        assert_eq!(get_tunneling_sparse(-1., &dip_system, 1e-10, 200), TunnelingResult::NotFound);
+
+        for (tolerance, max_iter) in [(0., 500), (f64::NAN, 500), (1e-10, 0)] {
+            assert!(matches!(parse_solver_str("sparse", 1e-2, 1e-6, tolerance, max_iter), Err(Error::Config(_))));
+        }
+    }
+
+    #[test]
+    fn higher_lobe_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        dip_system.update_occupation(get_filled(&dip_system.latt)*2).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let (lower, upper) = get_mu_inequality(&dip_system);
+        assert_relative_eq!(lower, 28.);
+        assert_relative_eq!(upper, 48.);
+
+        // particle and hole energies are both 10 at mu = 38
+        assert_relative_eq!(get_tunneling_eigen(38., &dip_system).unwrap(), 0.5, epsilon = 1e-10);
+        assert_relative_eq!(get_tunneling_momentum(38., &dip_system, None).unwrap().unwrap(), 0.5, epsilon = 1e-10);
+
+        dip_system.update_occupation(fill_pattern(&get_checkerboard(&dip_system.latt), (2, 1))).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let (lower, upper) = get_mu_inequality(&dip_system);
+        assert_relative_eq!(lower, 20. + 4.);
+        assert_relative_eq!(upper, 20. + 8.);
+        assert!(get_tunneling_bisect(26., &dip_system, 4., 1e-2, 1e-9).tunneling().is_some());
+
+        // lobes in mu order alternate the checkerboard and the uniform filling
+        let cb = get_checkerboard(&dip_system.latt);
+        let filled = get_filled(&dip_system.latt);
+        let lobe_1 = get_next_lobe(&cb, &cb).unwrap();
+        assert_eq!(lobe_1, filled);
+        let lobe_2 = get_next_lobe(&lobe_1, &cb).unwrap();
+        assert_eq!(lobe_2, fill_pattern(&cb, (2, 1)));
+        assert_eq!(get_next_lobe(&lobe_2, &cb).unwrap(), &filled*2);
+        assert_eq!(get_next_lobe(&(&filled*2), &filled).unwrap(), &filled*3);
+        assert!(matches!(get_next_lobe(&fill_pattern(&cb, (255, 255)), &cb), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn phase_diagram_test() {
+        let dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        let candidates = get_candidate_patterns(&dip_system.latt, 1);
+        let labels: Vec<&str> = candidates.iter().map(|c| c.label.as_str()).collect();
+        assert_eq!(labels, ["filled_0", "filled_1", "cb_1_0", "hstripe_1_0"]);
+
+        let params = DiagramParams::new((0., 24.), 25, 1);
+        let diagram = get_phase_diagram(&candidates, &dip_system, &params, &Solver::Eigen, false, 1).unwrap();
+
+        // every candidate touches the window, the stripe only at mu = 2
+        assert_eq!(diagram.lobes.len(), 4);
+        let point = |mu: f64| diagram.mu.iter().position(|m| (m - mu).abs() < 1e-12).unwrap();
+
+        // the checkerboard and the filled lobe meet at mu = 4
+        assert_eq!(diagram.tunneling[point(4.)], 0.);
+        assert_eq!(diagram.pattern[point(2.)], Some(2));
+        assert_eq!(diagram.max_pattern[point(2.)], Some(2));
+        assert!(diagram.max_tunneling.iter().zip(diagram.tunneling.iter()).all(|(max, t_c)| max >= t_c));
+
+        // particle and hole energies of the filled lobe are both 10 at mu = 14
+        assert_eq!(diagram.pattern[point(14.)], Some(1));
+        assert_relative_eq!(diagram.tunneling[point(14.)], 1./1.2, epsilon = 1e-10);
+    }
+
+    #[test]
+    fn lobe_tip_extrapolation_test() {
+        let mu_vals = DVector::from_fn(11, |idx, _| idx as f64);
+        let tunneling = mu_vals.map(|mu| 1. - 0.01*(mu - 4.3).powi(2));
+
+        let tip = get_lobe_tip(&mu_vals, &tunneling).unwrap();
+        assert_relative_eq!(tip.mu, 4.3, epsilon = 1e-10);
+        assert_relative_eq!(tip.tunneling, 1., epsilon = 1e-10);
+        assert!(get_lobe_tip(&mu_vals, &DVector::zeros(11)).is_none());
+
+        let sizes = [4., 6., 8.];
+        let values: Vec<f64> = sizes.iter().map(|size: &f64| 0.5 + 2./size.powi(2)).collect();
+
+        let fit = fit_finite_size(&sizes, &values, 2).unwrap();
+        assert_relative_eq!(fit.intercept, 0.5, epsilon = 1e-12);
+        assert_relative_eq!(fit.slope, 2., epsilon = 1e-12);
+        assert!(fit.intercept_err.unwrap() < 1e-12);
+
+        let fit = fit_finite_size(&sizes[..2], &values[..2], 1).unwrap();
+        assert!(fit.intercept_err.is_none());
+        assert!(fit_finite_size(&sizes[..1], &values[..1], 1).is_none());
+    }
+
+    #[test]
+    fn potential_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+
+        let (lower, upper) = get_mu_inequality(&dip_system);
+        let t_c = get_tunneling_eigen(14., &dip_system).unwrap();
+
+        // a uniform potential shifts mu
+        dip_system.update_potential(DMatrix::from_element(4, 4, 3.)).unwrap();
+        let (lower_shifted, upper_shifted) = get_mu_inequality(&dip_system);
+        assert_relative_eq!(lower_shifted, lower + 3.);
+        assert_relative_eq!(upper_shifted, upper + 3.);
+        assert_relative_eq!(get_particle_e(1, 2, 17., &dip_system), 10.);
+        assert_relative_eq!(get_hole_e(1, 2, 17., &dip_system), 10.);
+        assert_relative_eq!(get_tunneling_eigen(17., &dip_system).unwrap(), t_c, epsilon = 1e-12);
+
+        let trap = get_harmonic_potential(&dip_system.latt, 2., None);
+        assert_relative_eq!(trap[(0, 0)], 2.*(1.5f64.powi(2) + 1.5f64.powi(2)));
+        assert_relative_eq!(trap[(1, 2)], 2.*(0.5f64.powi(2) + 0.5f64.powi(2)));
+        assert_relative_eq!(get_harmonic_potential(&dip_system.latt, 1., Some((0., 0.)))[(3, 1)], 10.);
+
+        // the trap breaks the translation invariance
+        dip_system.update_potential(trap).unwrap();
+        assert_eq!(find_unit_cell(&dip_system), (4, 4));
+        let (lower, upper) = get_mu_inequality(&dip_system);
+        let mu = 0.5*(lower + upper);
+        assert_relative_eq!(get_tunneling_momentum(mu, &dip_system, None).unwrap().unwrap(),
+                            get_tunneling_eigen(mu, &dip_system).unwrap(), epsilon = 1e-10);
+
+        let energy = get_energy(mu, &dip_system);
+        assert_relative_eq!(energy.potential, dip_system.potential.sum());
+        assert_relative_eq!(get_site_energies(mu, &dip_system).sum(), energy.total(), epsilon = 1e-10);
+    }
+
+    #[test]
+    fn wedding_cake_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 6).unwrap();
+        dip_system.update_potential(get_harmonic_potential(&dip_system.latt, 3., None)).unwrap();
+
+        // local mu from 28.5 in the centre to -7.5 in the corners
+        let result = find_ground_state(30., &dip_system, 3, &AnnealingSchedule::default(), 0, 2);
+        assert_eq!(result.occupation[(2, 2)], 2);
+        assert_eq!(result.occupation[(0, 0)], 0);
+    }
+
+    #[test]
+    fn disorder_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        let t_c = get_tunneling_eigen(14., &dip_system).unwrap();
+
+        let disorder = Disorder::new(2., 0.4, 3, 7);
+        assert_eq!(disorder.seeds(), [7, 8, 9]);
+
+        let mut disordered = dip_system.clone();
+        apply_disorder(&mut disordered, &disorder, 7);
+        assert!(disordered.potential.iter().all(|v| v.abs() <= 1.));
+        assert!(disordered.bond_tunneling.values().all(|bond| (bond - 1.).abs() <= 0.2));
+        // every bond of the 4x4 square lattice
+        assert_eq!(disordered.bond_tunneling.len(), 32);
+        assert_eq!(disordered.get_bond_tunneling(1, 0), disordered.get_bond_tunneling(0, 1));
+
+        // the same seed gives the same realisation
+        let mut repeated = dip_system.clone();
+        apply_disorder(&mut repeated, &disorder, 7);
+        assert_eq!(repeated.potential, disordered.potential);
+        assert_eq!(repeated.bond_tunneling, disordered.bond_tunneling);
+
+        let mut other = dip_system.clone();
+        apply_disorder(&mut other, &disorder, 8);
+        assert_ne!(other.potential, disordered.potential);
+
+        // the solvers agree with disordered bonds
+        let t_eigen = get_tunneling_eigen(14., &disordered).unwrap();
+        assert_relative_eq!(get_tunneling_sparse(14., &disordered, 1e-12, 500).tunneling().unwrap(), t_eigen, epsilon = 1e-8);
+        assert_relative_eq!(get_tunneling_bisect(14., &disordered, 4., 1e-2, 1e-10).tunneling().unwrap(),
+                            t_eigen, epsilon = 1e-8);
+        assert!((t_eigen - t_c).abs() > 1e-6);
+
+        // next-nearest neighbour bonds are disordered as well
+        let mut nnn = dip_system.clone();
+        nnn.hopping = Hopping::new(1., 1., 1., 0.25);
+        apply_disorder(&mut nnn, &disorder, 7);
+        assert_eq!(nnn.bond_tunneling.len(), 64);
+        assert_ne!(nnn.get_bond_tunneling(0, nnn.latt.get_spin_idx(1, 1)), 1.);
+
+        // no disorder leaves the system clean
+        let mut clean = dip_system.clone();
+        apply_disorder(&mut clean, &Disorder::new(0., 0., 1, 0), 0);
+        assert!(clean.bond_tunneling.is_empty());
+        assert_relative_eq!(get_tunneling_eigen(14., &clean).unwrap(), t_c);
+    }
+
+    #[test]
+    fn hopping_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        dip_system.hopping = Hopping::new(1., 0.5, 1., 0.25);
+
+        let hoppings = dip_system.get_hoppings(1, 1);
+        assert_eq!(hoppings.len(), 8);
+        assert!(hoppings.contains(&(2, 1, 1.)));
+        assert!(hoppings.contains(&(1, 2, 0.5)));
+        assert!(hoppings.contains(&(2, 2, 0.25)));
+
+        // coefficients of -0.3 at mu = 14 and the largest eigenvalue
+        // 2*tx + 2*ty + 4*t_nnn of the hopping matrix at k = 0
+        let t_c = 1./(0.3*(2. + 1. + 1.));
+        assert_relative_eq!(get_tunneling_eigen(14., &dip_system).unwrap(), t_c, epsilon = 1e-10);
+        assert_relative_eq!(get_tunneling_momentum(14., &dip_system, None).unwrap().unwrap(), t_c, epsilon = 1e-10);
+        assert_relative_eq!(get_tunneling_sparse(14., &dip_system, 1e-12, 500).tunneling().unwrap(), t_c, epsilon = 1e-8);
+        assert_relative_eq!(get_tunneling_bisect(14., &dip_system, 4., 1e-2, 1e-10).tunneling().unwrap(),
+                            t_c, epsilon = 1e-8);
+
+        // bonds without tunneling are left out
+        dip_system.hopping = Hopping::new(1., 0., 1., 0.);
+        assert_eq!(dip_system.get_hoppings(1, 1).len(), 2);
+
+        // triangular bonds at 60 degrees mix tx and ty
+        let hopping = Hopping::new(1., 0.5, 1., 0.);
+        let latt = PeriodicLattice::new_geometry(Geometry::Triangular, 4, 4).unwrap();
+        assert_relative_eq!(hopping.get_amplitude(&latt.get_bond_vec(0, 1, 0, 0)), 1.);
+        assert_relative_eq!(hopping.get_amplitude(&latt.get_bond_vec(0, 0, 1, 0)), 0.25 + 0.75*0.5);
+
+        // interlayer bonds have tz
+        let hopping = Hopping::new(1., 1., 0.3, 0.);
+        let latt = PeriodicLattice::new(4).unwrap().with_stacking(Stacking::Open(2, 1.5)).unwrap();
+        assert_relative_eq!(hopping.get_amplitude(&latt.get_bond_vec(0, 0, 0, 1)), 0.3);
+    }
+
+    #[test]
+    fn hopping_solver_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        dip_system.update_occupation(get_filled(&dip_system.latt)).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        let mu = 10.;
+
+        // weak, anisotropic and decoupled bonds, with critical tunnelings above 4 and at degenerate zeros
+        for hopping in [Hopping::new(0.2, 0.2, 1., 0.), Hopping::new(1., 0.3, 1., 0.), Hopping::new(1., 0., 1., 0.)] {
+            dip_system.hopping = hopping;
+            let eigen = get_tunneling_eigen(mu, &dip_system).unwrap();
+
+            assert!(eigen <= get_max_tunneling(mu, &dip_system).unwrap());
+            assert_relative_eq!(solve_tunneling(mu, &dip_system, &Solver::Bisection { bracket_step: 1e-2, tolerance: 1e-8 }),
+                                eigen, epsilon = 1e-7);
+            // the scan stops at the det(M) threshold just below the critical tunneling
+            let scan = solve_tunneling(mu, &dip_system, &Solver::Scan);
+            assert!(0. < scan && scan < eigen);
+        }
+
+        // no bonds
+        dip_system.hopping = Hopping::new(0., 0., 0., 0.);
+        assert!(get_max_tunneling(mu, &dip_system).is_none());
+        assert_eq!(solve_tunneling(mu, &dip_system, &Solver::Bisection { bracket_step: 1e-2, tolerance: 1e-8 }), 0.);
+    }
+
+    #[test]
+    fn bond_charge_test() {
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        dip_system.update_occupation(get_filled(&dip_system.latt)*2).unwrap();
+        generate_dd_int_mat(&mut dip_system);
+        let t_c = get_tunneling_eigen(38., &dip_system).unwrap();
+
+        // particle and hole energies are both 10 at mu = 38, so the
+        // coefficient is -(3*(1 + 2g)^2 + 2*(1 + g)^2)/10
+        dip_system.bond_charge = 0.1;
+        assert_relative_eq!(get_m_coeff(0, 0, 38., &dip_system), -(3.*1.44 + 2.*1.21)/10., epsilon = 1e-12);
+
+        let t_c_bond_charge = 1./(4.*(3.*1.44 + 2.*1.21)/10.);
+        assert_relative_eq!(get_tunneling_eigen(38., &dip_system).unwrap(), t_c_bond_charge, epsilon = 1e-10);
+        assert_relative_eq!(get_tunneling_bisect(38., &dip_system, 4., 1e-2, 1e-10).tunneling().unwrap(),
+                            t_c_bond_charge, epsilon = 1e-8);
+        assert!(t_c_bond_charge < t_c);
+
+        // the stability range does not depend on the tunneling
+        dip_system.bond_charge = 0.;
+        let (lower, upper) = get_mu_inequality(&dip_system);
+        dip_system.bond_charge = -0.2;
+        assert_eq!(get_mu_inequality(&dip_system), (lower, upper));
+        assert!(get_tunneling_eigen(38., &dip_system).unwrap() > t_c);
+    }
+
     #[test]
     fn get_mu_inequality_test() {
-        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 2);
-        dip_system.update_occupation(get_checkerboard(&dip_system.latt));
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 2).unwrap();
+        dip_system.update_occupation(get_checkerboard(&dip_system.latt)).unwrap();
         generate_dd_int_mat(&mut dip_system);
 
         let (lower, upper) = get_mu_inequality(&dip_system);
@@ -152,4 +1258,133 @@ mod tests {
         assert_relative_eq!(arr[0], 0.);
         assert_relative_eq!(arr[1], 1.);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sweep_axis_test() {
+        assert_eq!(get_sweep_axis(0.3, None, 5), DVector::from_element(1, 0.3));
+        assert_eq!(get_sweep_axis(0.3, Some(0.5), 1), DVector::from_element(1, 0.3));
+
+        let axis = get_sweep_axis(0.2, Some(0.4), 3);
+        assert_eq!(axis.len(), 3);
+        assert_relative_eq!(axis[1], 0.3);
+    }
+
+    #[test]
+    fn parallel_map_test() {
+        let values = linspace(0., 1., 11, true);
+        let serial = values.map(|v| v*v);
+
+        for num_threads in [0, 1, 3, 20] {
+            assert_eq!(parallel_map(&values, num_threads, |v| v*v), serial);
+        }
+    }
+
+    #[test]
+    fn lattice_error_test() {
+        assert!(matches!(PeriodicLattice::new(0), Err(Error::Config(_))));
+        assert!(matches!(PeriodicLattice::new_rect(4, 0), Err(Error::Config(_))));
+        assert!(matches!(PeriodicLattice::new_cubic(0), Err(Error::Config(_))));
+        assert!(matches!(PeriodicLattice::new_geometry(Geometry::Kagome, usize::MAX/2, 2), Err(Error::Config(_))));
+        assert!(matches!(DipolarSystem::new(0., 0., 20., 1, 0), Err(Error::Config(_))));
+        assert!(matches!(PeriodicLattice::new(4).unwrap().with_stacking(Stacking::Open(0, 1.)),
+                         Err(Error::Config(_))));
+        assert!(matches!(PeriodicLattice::new(4).unwrap().with_stacking(Stacking::Periodic(usize::MAX, 1.)),
+                         Err(Error::Config(_))));
+
+        assert_eq!(linspace(0., 1., 0, true).len(), 0);
+        assert_eq!(linspace(0., 1., 1, true), DVector::from_element(1, 0.));
+
+        // the FFT of the d-d interaction needs a periodic lattice and its own kernel
+        let mut dip_system = DipolarSystem::new(0., 0., 20., 1, 4).unwrap();
+        let kernel = get_dd_kernel(&dip_system).unwrap();
+        let kernel_6 = get_dd_kernel(&DipolarSystem::new(0., 0., 20., 1, 6).unwrap()).unwrap();
+        assert!(matches!(convolve_dd_kernel(&dip_system, &kernel_6), Err(Error::Shape { .. })));
+        assert!(matches!(DdKernel::new(Vec::new(), &dip_system.latt), Err(Error::Shape { .. })));
+        assert!(matches!(DdKernel::new(kernel_6.blocks.clone(), &dip_system.latt), Err(Error::Shape { .. })));
+
+        dip_system.latt = dip_system.latt.with_boundary(Boundary::Open).unwrap();
+        assert!(matches!(convolve_dd_kernel(&dip_system, &kernel), Err(Error::Unsupported(_))));
+        assert!(matches!(get_dd_field_fft(&DMatrix::zeros(4, 4), &dip_system, &kernel), Err(Error::Unsupported(_))));
+        assert!(matches!(generate_dd_int_mat_fft(&mut dip_system, &kernel), Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn error_test() {
+        let mut dip_system = DipolarSystem::new_rect(0., 0., 20., 1, 4, 6).unwrap();
+        let occupation = get_checkerboard(&PeriodicLattice::new(4).unwrap());
+
+        match dip_system.update_occupation(occupation) {
+            Err(Error::Shape { expected, found, .. }) => {
+                assert_eq!(expected, (6, 4));
+                assert_eq!(found, (4, 4));
+            },
+            other => panic!("expected a shape error, got {other:?}"),
+        }
+        assert!(dip_system.update_potential(DMatrix::zeros(6, 4)).is_ok());
+
+        let dir = std::env::temp_dir().join("mean_field_dipolar_error_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let ragged = dir.join("ragged.json");
+        save_json(&ragged, &vec![vec![1., 2.], vec![3.]]).unwrap();
+        assert!(matches!(load_matrix_json(&ragged), Err(Error::Shape { .. })));
+        assert!(matches!(load_matrix_json(dir.join("missing.json")), Err(Error::Io(_))));
+        assert!(matches!(parse_potential_str("map", 0., None, None), Err(Error::Config(_))));
+        assert!(matches!(parse_potential_str("harmonics", 0., None, None), Err(Error::Config(_))));
+        assert!(matches!(parse_solver_str("eigne", 1e-2, 1e-6, 1e-10, 500), Err(Error::Config(_))));
+
+        // finite size fits in 1/L^power with a power other than 1 or 2
+        let config = |power: u32| toml::from_str::<Config>(&format!(
+            "pattern = \"cb\"\nrange_start = 1\nrange_end = 1\nsize_start = 4\nsize_end = 4\n\
+             theta = 0.0\nphi = 0.0\nu_onsite = 20.0\nextrapolate = true\nextrapolation_power = {power}")).unwrap();
+        for power in [0, 3] {
+            assert!(matches!(parse_config(config(power)), Err(Error::Config(_))));
+        }
+        assert_eq!(parse_config(config(2)).unwrap().extrapolation_power, Some(2));
+        assert!(save_json(dir.join("missing").join("out.json"), &[1.]).is_err());
+
+        // a potential map which fits none of the sizes
+        let sweep = |configure: &dyn Fn(&mut SweepParams)| {
+            let mut params = SweepParams::new(Pattern::CB(1, 0), (1, 2), (4, 6), 20.);
+            params.solver = Solver::Eigen;
+            configure(&mut params);
+            simulation_sweep(&dir, &params)
+        };
+        assert!(matches!(sweep(&|params| params.potential = Potential::Map(DMatrix::zeros(3, 3))),
+                         Err(Error::Config(_))));
+
+        // the momentum solver with bond disorder
+        assert!(matches!(sweep(&|params| {
+                             params.solver = Solver::Momentum;
+                             params.disorder = Some(Disorder::new(0., 0.1, 1, 0));
+                         }),
+                         Err(Error::Config(_))));
+
+        // the momentum solver on open boundaries and lattices without layers
+        assert!(matches!(sweep(&|params| {
+                             params.solver = Solver::Momentum;
+                             params.boundary = Boundary::Open;
+                         }),
+                         Err(Error::Config(_))));
+        assert!(matches!(sweep(&|params| params.stacking = Stacking::Open(0, 1.)), Err(Error::Config(_))));
+
+        // the enumeration on boundaries which are not periodic and in a trap
+        assert!(matches!(sweep(&|params| {
+                             params.enumeration = Some(EnumerationParams::new(2, 1, (0., 1.)));
+                             params.boundary = Boundary::Open;
+                         }),
+                         Err(Error::Config(_))));
+        assert!(matches!(sweep(&|params| {
+                             params.enumeration = Some(EnumerationParams::new(2, 1, (0., 1.)));
+                             params.potential = Potential::Harmonic { strength: 0.1, centre: None };
+                         }),
+                         Err(Error::Config(_))));
+
+        // the lattice sum on boundaries which are not periodic
+        assert!(matches!(sweep(&|params| {
+                             params.lattice_sum = true;
+                             params.boundary = Boundary::CylinderX;
+                         }),
+                         Err(Error::Config(_))));
+
+    }
+}
@@ -2,11 +2,10 @@ extern crate nalgebra as na;
 extern crate serde_json;
 extern crate toml;
 
-use mean_field_dipolar::dipolar::{simulation_sweep};
+use mean_field_dipolar::dipolar::simulation_sweep;
+use mean_field_dipolar::error::Error;
 use mean_field_dipolar::util;
-use std::f64::consts::PI;
 use std::path::{Path, PathBuf};
-use std::fs;
 use clap::{Parser};
 
 #[derive(Parser)]
@@ -15,11 +14,23 @@ struct Cli {
     /// Simulation results path
     #[arg(short, long, value_name = "FILE")]
     respath: Option<PathBuf>,
+
+    /// Number of threads, 0 uses all available cores.
+    /// Overrides the threads value in the config
+    #[arg(short, long)]
+    threads: Option<usize>,
 }
 
 fn main() {
     let cli = Cli::parse();
 
+    if let Err(err) = run(cli) {
+        eprintln!("Error: {err}");
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Error> {
     // save path
     let save_path = match cli.respath.as_deref() {
         Some(path) => Path::new(path),
@@ -31,17 +42,12 @@ fn main() {
     let config_path = Path::new("./sim.toml");
 
     // read the config
-    let config_str = fs::read_to_string(config_path)
-        .expect("Config file wasn't found");
-
-    let config: util::Config = toml::from_str(config_str.as_ref())
-        .expect("Failed reading config file");
+    let config = util::load_config(config_path)?;
 
-    let patt = util::parse_pattern_str(config.pattern);
+    let mut params = util::parse_config(config)?;
+    if let Some(threads) = cli.threads {
+        params.num_threads = threads;
+    }
 
-    simulation_sweep(save_path, &patt, 
-                     (config.range_start, config.range_end+1),
-                     (config.size_start, config.size_end+1),
-                     config.theta*PI,
-                     config.phi*PI, config.u_onsite);
+    simulation_sweep(save_path, &params)
 }
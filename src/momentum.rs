@@ -0,0 +1,159 @@
+use std::f64::consts::PI;
+
+use na::{Complex, DMatrix};
+
+use crate::dipolar::{DipolarSystem, get_m_coeff};
+use crate::error::{Error, Result};
+use crate::lattice::Boundary;
+
+/// Whether the system is invariant under a shift by (dx, dy)
+/// columns and rows of the lattice matrices
+fn is_shift_invariant(dip: &DipolarSystem, dx: usize, dy: usize) -> bool {
+    let lx = dip.latt.ncols();
+    let ly = dip.latt.size_y;
+
+    // dd_mat from generate_dd_int_mat_fft carries rounding errors
+    let is_close = |a: f64, b: f64| (a - b).abs() <= 1e-10*(1. + a.abs());
+
+    (0..lx).all(|x| (0..ly).all(|y| {
+        dip.occupation[(y, x)] == dip.occupation[((y + dy)%ly, (x + dx)%lx)]
+            && is_close(dip.dd_mat[(y, x)], dip.dd_mat[((y + dy)%ly, (x + dx)%lx)])
+            && is_close(dip.potential[(y, x)], dip.potential[((y + dy)%ly, (x + dx)%lx)])
+    }))
+}
+
+/// Whether (cx, cy) is a unit cell of the system, see find_unit_cell
+pub fn is_unit_cell(dip: &DipolarSystem, unit_cell: (usize, usize)) -> bool {
+    let (cx, cy) = unit_cell;
+
+    cx > 0 && cy > 0 && cx.is_multiple_of(dip.latt.n_cell_sites())
+        && dip.latt.ncols().is_multiple_of(cx) && dip.latt.size_y.is_multiple_of(cy)
+        && is_shift_invariant(dip, cx, 0) && is_shift_invariant(dip, 0, cy)
+}
+
+/// Find the smallest rectangular unit cell (cx, cy) of the system,
+/// in columns and rows of the lattice matrices.
+///
+/// The occupation, the d-d interaction matrix and the potential need
+/// to be periodic with the unit cell (up to rounding), and cx, cy need to
+/// divide latt.ncols(), size_y. cx is a multiple of the number of
+/// cell sites. Falls back to the whole lattice if no smaller cell
+/// exists.
+pub fn find_unit_cell(dip: &DipolarSystem) -> (usize, usize) {
+    let lx = dip.latt.ncols();
+    let ly = dip.latt.size_y;
+    let n_cell_sites = dip.latt.n_cell_sites();
+
+    let cx = (1..lx + 1)
+        .find(|&cx| cx.is_multiple_of(n_cell_sites) && lx.is_multiple_of(cx) && is_shift_invariant(dip, cx, 0))
+        .unwrap();
+    let cy = (1..ly + 1).find(|&cy| ly.is_multiple_of(cy) && is_shift_invariant(dip, 0, cy)).unwrap();
+
+    (cx, cy)
+}
+
+/// Generate the symmetrised hopping matrix S_k of a single momentum block
+///
+/// Sites of the unit cell are indexed as ay*cx + ax, and the Bloch
+/// phase of a hop is exp(i k.delta) with delta the hop in units of
+/// the primitive vectors. The hops and their amplitudes are the ones
+/// of get_hopping_offsets, where hops which wrap onto the same site of
+/// the periodic lattice are counted once, as in get_hoppings. See
+/// get_tunneling_eigen for the definition of S.
+pub fn generate_mat_s_k(k: (f64, f64), unit_cell: (usize, usize),
+                        coeffs: &DMatrix<f64>, dip: &DipolarSystem) -> DMatrix<Complex<f64>> {
+    let (cx, cy) = unit_cell;
+    let latt = &dip.latt;
+    let n_cell_sites = latt.n_cell_sites();
+    let (size_x, size_y) = (latt.size_x as isize, latt.size_y as isize);
+    let hopping_offsets: Vec<Vec<_>> = (0..n_cell_sites).map(|s| {
+        let mut offsets: Vec<(isize, isize, usize, f64)> = Vec::new();
+
+        for (n1, n2, s_n, hop) in dip.get_hopping_offsets(s) {
+            if offsets.iter().all(|&(m1, m2, s_m, _)| {
+                (n1 - m1).rem_euclid(size_x) != 0 || (n2 - m2).rem_euclid(size_y) != 0 || s_n != s_m
+            }) {
+                offsets.push((n1, n2, s_n, hop));
+            }
+        }
+        offsets
+    }).collect();
+    let cells_x = (cx/n_cell_sites) as isize;
+    let mut s_k = DMatrix::zeros(cx*cy, cx*cy);
+
+    for ax in 0..cx {
+        for ay in 0..cy {
+            let cell_x = (ax/n_cell_sites) as isize;
+
+            for &(n1, n2, s_n, hop) in &hopping_offsets[ax%n_cell_sites] {
+                let bx = (cell_x + n1).rem_euclid(cells_x) as usize*n_cell_sites + s_n;
+                let by = (ay as isize + n2).rem_euclid(cy as isize) as usize;
+
+                let angle = k.0*n1 as f64 + k.1*n2 as f64;
+                let phase = Complex::new(angle.cos(), angle.sin());
+                let amplitude = (coeffs[(ay, ax)]*coeffs[(by, bx)]).sqrt()*hop;
+
+                s_k[(ay*cx + ax, by*cx + bx)] += phase*amplitude;
+            }
+        }
+    }
+
+    s_k
+}
+
+/// Find smallest tunneling where det(M)=0 using the momentum space
+/// block structure of M.
+///
+/// For an occupation periodic with unit_cell (found with find_unit_cell
+/// if None), M is block diagonal in the momenta
+/// k = (2 pi mx / size_x, 2 pi my / size_y) of the unit cell lattice,
+/// with size_x, size_y in cells of the geometry.
+/// The critical tunneling is 1/lambda_max over all blocks, so the cost
+/// scales with the number of blocks instead of with the full
+/// no_sites x no_sites matrix.
+///
+/// Gives the same critical tunneling as get_tunneling_eigen.
+///
+/// Returns Ok(None) if mu lies outside the stability region of the
+/// occupation or no positive critical tunneling exists, and an error
+/// if the lattice boundary is not periodic, the bonds have different
+/// tunnelings or the system is not periodic with the given unit cell.
+pub fn get_tunneling_momentum(mu: f64, dip: &DipolarSystem,
+                              unit_cell: Option<(usize, usize)>) -> Result<Option<f64>> {
+    let latt = &dip.latt;
+    if latt.boundary != Boundary::Periodic {
+        return Err(Error::Unsupported("the momentum space solver requires periodic boundaries"))
+    }
+    if !dip.bond_tunneling.is_empty() {
+        return Err(Error::Unsupported("the momentum space solver requires uniform bond tunneling"))
+    }
+
+    let (cx, cy) = match unit_cell {
+        Some(unit_cell) if !is_unit_cell(dip, unit_cell) => return Err(Error::UnitCell { unit_cell }),
+        Some(unit_cell) => unit_cell,
+        None => find_unit_cell(dip),
+    };
+
+    let coeffs = DMatrix::from_fn(cy, cx, |ay, ax| get_m_coeff(ax, ay, mu, dip));
+
+    if coeffs.iter().any(|c| !c.is_finite() || *c >= 0.) {
+        return Ok(None)
+    }
+
+    let mut lambda_max = f64::NEG_INFINITY;
+
+    for mx in 0..latt.ncols()/cx {
+        for my in 0..latt.size_y/cy {
+            let k = (2.*PI*mx as f64/latt.size_x as f64, 2.*PI*my as f64/latt.size_y as f64);
+            let s_k = generate_mat_s_k(k, (cx, cy), &coeffs, dip);
+
+            lambda_max = lambda_max.max(s_k.symmetric_eigenvalues().max());
+        }
+    }
+
+    if lambda_max > 0. {
+        Ok(Some(1./lambda_max))
+    } else {
+        Ok(None)
+    }
+}
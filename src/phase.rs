@@ -0,0 +1,187 @@
+use na::{DMatrix, DVector};
+
+use crate::diagram::get_candidate_patterns;
+use crate::dipolar::DipolarSystem;
+use crate::gutzwiller::{GutzwillerState, solve_gutzwiller};
+use crate::lattice::PeriodicLattice;
+use crate::util;
+
+/// Order parameter and density modulation below which
+/// they are considered zero
+pub const ORDER_THRESHOLD: f64 = 1e-4;
+
+/// Sublattice structure of a density
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DensityOrder {
+    Uniform,
+    /// alternating along both directions, see get_checkerboard
+    Checkerboard,
+    /// alternating rows, see get_horizontal_stripe
+    HStripe,
+    /// alternating columns
+    VStripe,
+    /// any other modulation
+    Other,
+}
+
+impl DensityOrder {
+    pub fn label(&self) -> &'static str {
+        match self {
+            DensityOrder::Uniform => "uniform",
+            DensityOrder::Checkerboard => "cb",
+            DensityOrder::HStripe => "hstripe",
+            DensityOrder::VStripe => "vstripe",
+            DensityOrder::Other => "other",
+        }
+    }
+}
+
+/// Phase of a mean-field ground state
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// empty lattice
+    Vacuum,
+    /// uniform nonzero density without superfluid order
+    MottInsulator,
+    /// modulated density without superfluid order
+    DensityWave(DensityOrder),
+    /// uniform density with superfluid order
+    Superfluid,
+    /// modulated density with superfluid order
+    Supersolid(DensityOrder),
+}
+
+impl Phase {
+    /// Short label used in the phase map output, e.g. "MI" or "SS_cb"
+    pub fn label(&self) -> String {
+        match self {
+            Phase::Vacuum => "VAC".to_string(),
+            Phase::MottInsulator => "MI".to_string(),
+            Phase::DensityWave(order) => format!("DW_{}", order.label()),
+            Phase::Superfluid => "SF".to_string(),
+            Phase::Supersolid(order) => format!("SS_{}", order.label()),
+        }
+    }
+}
+
+/// Grid and local Fock space of a phase map
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PhaseMapGrid {
+    pub mu_range: (f64, f64),
+    /// tunneling runs from 0 to t_max
+    pub t_max: f64,
+    /// number of points along mu and t
+    pub no_points: usize,
+    /// largest occupation of the local Fock space
+    pub n_max: usize,
+}
+
+impl PhaseMapGrid {
+    pub fn new(mu_range: (f64, f64), t_max: f64, no_points: usize, n_max: usize) -> Self {
+        PhaseMapGrid { mu_range, t_max, no_points, n_max }
+    }
+}
+
+/// Classify the sublattice structure of a density
+///
+/// The density is uniform if it varies by less than threshold. Otherwise
+/// it is a checkerboard or stripe if it takes two values alternating with
+/// the rows and the columns of the layer, see get_layer_col, up to threshold.
+pub fn classify_density(density: &DMatrix<f64>, latt: &PeriodicLattice, threshold: f64) -> DensityOrder {
+    if density.max() - density.min() < threshold {
+        return DensityOrder::Uniform
+    }
+
+    let mean = density.mean();
+    for order in [DensityOrder::Checkerboard, DensityOrder::HStripe, DensityOrder::VStripe] {
+        let sign = |row: usize, col: usize| {
+            let col = latt.get_layer_col(col);
+            let parity = match order {
+                DensityOrder::Checkerboard => (row + col)%2,
+                DensityOrder::HStripe => row%2,
+                _ => col%2,
+            };
+
+            if parity == 0 { 1. } else { -1. }
+        };
+        let amplitude = DMatrix::from_fn(density.nrows(), density.ncols(),
+                                         |row, col| sign(row, col)*(density[(row, col)] - mean))
+            .mean();
+
+        let residual = DMatrix::from_fn(density.nrows(), density.ncols(), |row, col| {
+            density[(row, col)] - mean - sign(row, col)*amplitude
+        }).amax();
+
+        if residual < threshold {
+            return order
+        }
+    }
+
+    DensityOrder::Other
+}
+
+/// Classify a Gutzwiller state by its order parameter and density
+pub fn classify_phase(state: &GutzwillerState, latt: &PeriodicLattice, threshold: f64) -> Phase {
+    let order = classify_density(&state.density, latt, threshold);
+    let superfluid = state.max_order_param() >= threshold;
+
+    match (superfluid, order) {
+        (false, DensityOrder::Uniform) if state.mean_density() < threshold => Phase::Vacuum,
+        (false, DensityOrder::Uniform) => Phase::MottInsulator,
+        (false, order) => Phase::DensityWave(order),
+        (true, DensityOrder::Uniform) => Phase::Superfluid,
+        (true, order) => Phase::Supersolid(order),
+    }
+}
+
+/// Get the initial states of the phase map, the occupation of dip
+/// and the uniform, checkerboard and stripe fillings up to n_max,
+/// each with a small order parameter seed
+fn get_initial_states(dip: &DipolarSystem, n_max: usize) -> Vec<GutzwillerState> {
+    let mut work = dip.clone();
+    let mut occupations = vec![dip.occupation.clone()];
+
+    // fillings of the candidates are u8, larger Fock spaces are truncated
+    for candidate in get_candidate_patterns(&dip.latt, n_max.min(u8::MAX as usize) as u8) {
+        if !occupations.contains(&candidate.occupation) {
+            occupations.push(candidate.occupation);
+        }
+    }
+
+    occupations.into_iter().map(|occupation| {
+        work.occupation = occupation;
+        GutzwillerState::new(&work, n_max, 0.1)
+    }).collect()
+}
+
+/// Get the phase of every (mu, t) point
+///
+/// Each point is solved with solve_gutzwiller from the occupation of
+/// dip and the uniform, checkerboard and stripe fillings up to n_max,
+/// and the converged state with the lowest energy is classified, so
+/// the map does not depend on the occupation of dip. Returns a
+/// t_vals.len() x mu_vals.len() matrix, with the mu points evaluated
+/// in parallel on num_threads threads.
+pub fn get_phase_map(mu_vals: &DVector<f64>, t_vals: &DVector<f64>, dip: &DipolarSystem,
+                     n_max: usize, num_threads: usize) -> DMatrix<Phase> {
+    let initial_states = get_initial_states(dip, n_max);
+    let mut phases = DMatrix::from_element(t_vals.len(), mu_vals.len(), Phase::MottInsulator);
+
+    for (row, &t) in t_vals.iter().enumerate() {
+        let phases_t = util::parallel_map(mu_vals, num_threads, |mu| {
+            // unconverged states are only used if no state converged
+            let state = initial_states.iter()
+                .map(|initial| solve_gutzwiller(mu, t, dip, initial.clone(), 1e-8, 2000))
+                .min_by(|a, b| b.converged.cmp(&a.converged).then(a.energy.total_cmp(&b.energy)))
+                .unwrap();
+            classify_phase(&state, &dip.latt, ORDER_THRESHOLD)
+        });
+
+        phases.set_row(row, &phases_t.transpose());
+    }
+
+    phases
+}
@@ -0,0 +1,90 @@
+use na::DVector;
+
+/// Tip of a Mott lobe, the largest critical tunneling and its mu
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct LobeTip {
+    pub mu: f64,
+    pub tunneling: f64,
+}
+
+/// Least squares fit of value = intercept + slope/L^power
+///
+/// The intercept is the extrapolation to infinite size. The standard
+/// errors need at least three sizes and are None otherwise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct FiniteSizeFit {
+    pub power: u32,
+    pub intercept: f64,
+    pub slope: f64,
+    pub intercept_err: Option<f64>,
+    pub slope_err: Option<f64>,
+}
+
+/// Get the lobe tip of a boundary sampled at mu_vals
+///
+/// The maximum is refined with the parabola through the largest sample
+/// and its neighbours. Returns None if no tunneling is positive.
+pub fn get_lobe_tip(mu_vals: &DVector<f64>, tunneling: &DVector<f64>) -> Option<LobeTip> {
+    let idx = tunneling.imax();
+    let tip = LobeTip { mu: mu_vals[idx], tunneling: tunneling[idx] };
+
+    if tip.tunneling <= 0. {
+        return None
+    }
+    if idx == 0 || idx + 1 == tunneling.len() {
+        return Some(tip)
+    }
+
+    let (x0, x1, x2) = (mu_vals[idx - 1], mu_vals[idx], mu_vals[idx + 1]);
+    let (y0, y1, y2) = (tunneling[idx - 1], tunneling[idx], tunneling[idx + 1]);
+
+    // vertex of the parabola through the three points
+    let d01 = (y1 - y0)/(x1 - x0);
+    let d12 = (y2 - y1)/(x2 - x1);
+    let curvature = (d12 - d01)/(x2 - x0);
+
+    if curvature >= 0. {
+        return Some(tip)
+    }
+    let mu = 0.5*(x0 + x1) - 0.5*d01/curvature;
+    let tunneling = y1 + d01*(mu - x1) + curvature*(mu - x0)*(mu - x1);
+
+    Some(LobeTip { mu, tunneling })
+}
+
+/// Fit values measured at the linear sizes to intercept + slope/L^power
+///
+/// Returns None if there are fewer than two different sizes.
+pub fn fit_finite_size(sizes: &[f64], values: &[f64], power: u32) -> Option<FiniteSizeFit> {
+    assert!(sizes.len() == values.len(), "sizes and values differ in length");
+
+    let no_points = sizes.len();
+    let x: Vec<f64> = sizes.iter().map(|size| size.powi(-(power as i32))).collect();
+
+    let x_mean = x.iter().sum::<f64>()/no_points as f64;
+    let y_mean = values.iter().sum::<f64>()/no_points as f64;
+    let sxx: f64 = x.iter().map(|xi| (xi - x_mean).powi(2)).sum();
+    let sxy: f64 = x.iter().zip(values).map(|(xi, yi)| (xi - x_mean)*(yi - y_mean)).sum();
+
+    if no_points < 2 || sxx <= 0. {
+        return None
+    }
+
+    let slope = sxy/sxx;
+    let intercept = y_mean - slope*x_mean;
+
+    let (intercept_err, slope_err) = if no_points > 2 {
+        let variance = x.iter().zip(values)
+            .map(|(xi, yi)| (yi - intercept - slope*xi).powi(2))
+            .sum::<f64>()/(no_points - 2) as f64;
+
+        (Some((variance*(1./no_points as f64 + x_mean.powi(2)/sxx)).sqrt()),
+         Some((variance/sxx).sqrt()))
+    } else {
+        (None, None)
+    };
+
+    Some(FiniteSizeFit { power, intercept, slope, intercept_err, slope_err })
+}
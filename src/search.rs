@@ -0,0 +1,344 @@
+use std::collections::HashSet;
+
+use na::DMatrix;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+
+use crate::dipolar::{DipolarSystem, generate_dd_int_mat, generate_dd_int_mat_fft, get_dd_kernel,
+                     get_dd_offsets, get_mu_inequality};
+use crate::energy::{get_energy, get_move_energy, set_site_occupation};
+use crate::error::{Error, Result};
+use crate::lattice::{Geometry, PeriodicLattice};
+
+/// Geometric temperature schedule of simulated annealing
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct AnnealingSchedule {
+    pub temp_start: f64,
+    pub temp_end: f64,
+    /// number of sweeps, each with one proposed move per site
+    pub no_sweeps: usize,
+}
+
+impl AnnealingSchedule {
+    pub fn new(temp_start: f64, temp_end: f64, no_sweeps: usize) -> Self {
+        AnnealingSchedule { temp_start, temp_end, no_sweeps }
+    }
+
+    /// Temperature of the given sweep
+    pub fn get_temp(&self, sweep: usize) -> f64 {
+        if self.no_sweeps < 2 {
+            return self.temp_end
+        }
+        let fraction = sweep as f64/(self.no_sweeps - 1) as f64;
+
+        self.temp_start*(self.temp_end/self.temp_start).powf(fraction)
+    }
+}
+
+impl Default for AnnealingSchedule {
+    fn default() -> Self {
+        Self::new(2., 1e-3, 2000)
+    }
+}
+
+/// Lowest energy occupation found by a search
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SearchResult {
+    pub occupation: DMatrix<u8>,
+    /// energy per site in the atomic limit
+    pub energy: f64,
+}
+
+/// Search the lowest energy occupation in the atomic (t=0) limit with
+/// simulated annealing
+///
+/// Every move proposes a new occupation 0..=n_max of a random site and
+/// is accepted with the Metropolis rule at the temperature of the
+/// schedule. The d-d interaction is updated locally after every
+/// accepted move. The search starts from the occupation of dip and
+/// returns the lowest energy occupation visited. The same seed always
+/// gives the same result.
+pub fn anneal_occupation(mu: f64, dip: &DipolarSystem, n_max: u8,
+                         schedule: &AnnealingSchedule, seed: u64) -> SearchResult {
+    let latt = &dip.latt;
+    let mut rng = Pcg64::seed_from_u64(seed);
+    let dd_offsets = get_dd_offsets(dip);
+
+    let mut work = dip.clone();
+    work.occupation = dip.occupation.map(|n| n.min(n_max));
+    generate_dd_int_mat(&mut work);
+
+    let mut energy = get_energy(mu, &work).total();
+    let mut best_energy = energy;
+    let mut best_occupation = work.occupation.clone();
+
+    // no other occupation to move to
+    let no_sweeps = if n_max > 0 { schedule.no_sweeps } else { 0 };
+
+    for sweep in 0..no_sweeps {
+        let temp = schedule.get_temp(sweep);
+
+        for _ in 0..latt.no_sites() {
+            let x = rng.gen_range(0..latt.ncols());
+            let y = rng.gen_range(0..latt.size_y);
+            let n = work.occupation[(y, x)];
+
+            // uniform over the other occupations
+            let n_new = ((n as u16 + rng.gen_range(1..n_max as u16 + 1))%(n_max as u16 + 1)) as u8;
+            let energy_change = get_move_energy(x, y, n_new, mu, &work);
+
+            if energy_change > 0. && rng.gen::<f64>() >= (-energy_change/temp).exp() {
+                continue
+            }
+
+            set_site_occupation(x, y, n_new, &mut work, &dd_offsets);
+            energy += energy_change;
+
+            if energy < best_energy {
+                best_energy = energy;
+                best_occupation = work.occupation.clone();
+            }
+        }
+    }
+
+    // avoid the rounding errors accumulated by the local updates
+    work.occupation = best_occupation;
+    generate_dd_int_mat(&mut work);
+
+    SearchResult { energy: get_energy(mu, &work).total()/latt.no_sites() as f64,
+                   occupation: work.occupation }
+}
+
+/// Search the lowest energy occupation with no_restarts independent
+/// annealing runs with seeds seed, seed + 1, ...
+pub fn find_ground_state(mu: f64, dip: &DipolarSystem, n_max: u8, schedule: &AnnealingSchedule,
+                         seed: u64, no_restarts: usize) -> SearchResult {
+    (0..no_restarts.max(1) as u64)
+        .map(|restart| anneal_occupation(mu, dip, n_max, schedule, seed + restart))
+        .min_by(|a, b| a.energy.total_cmp(&b.energy))
+        .unwrap()
+}
+
+/// Largest number of unit cell occupations tried by enumerate_patterns
+pub const MAX_PATTERNS: usize = 1 << 20;
+
+/// Parameters of the enumeration of periodic patterns
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct EnumerationParams {
+    /// largest number of sites of the unit cell
+    pub max_cell_sites: usize,
+    /// largest occupation of a site
+    pub n_max: u8,
+    /// mu window of the ground state ranges
+    pub mu_range: (f64, f64),
+}
+
+impl EnumerationParams {
+    pub fn new(max_cell_sites: usize, n_max: u8, mu_range: (f64, f64)) -> Self {
+        EnumerationParams { max_cell_sites, n_max, mu_range }
+    }
+}
+
+/// Periodic occupation pattern with its atomic limit energetics
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PeriodicPattern {
+    /// occupation of the whole lattice
+    pub occupation: DMatrix<u8>,
+    /// smallest unit cell (cx, cy) in columns and rows the pattern was found with
+    pub unit_cell: (usize, usize),
+    /// shear of the unit cell in columns, the pattern is periodic under
+    /// translations by (cx, 0) and (shear, cy) in (columns, rows)
+    pub shear: usize,
+    /// mean occupation per site
+    pub density: f64,
+    /// energy per site at mu = 0, the energy at mu is energy_zero - mu*density
+    pub energy_zero: f64,
+    /// mu range in which the pattern is stable against adding or
+    /// removing a particle, see get_mu_inequality
+    pub stable_range: (f64, f64),
+}
+
+impl PeriodicPattern {
+    /// Energy per site at the given mu
+    pub fn get_energy(&self, mu: f64) -> f64 {
+        self.energy_zero - mu*self.density
+    }
+}
+
+/// Get a representation of the occupation which is the same for all
+/// occupations related by a lattice translation, and for the square
+/// geometry with size_x = size_y by a rotation or reflection
+pub(crate) fn get_canonical_occupation(occupation: &DMatrix<u8>, latt: &PeriodicLattice) -> Vec<u8> {
+    let n_cell_sites = latt.n_cell_sites();
+    let (size_x, size_y) = (latt.size_x, latt.size_y);
+    let point_group = latt.geometry == Geometry::Square && size_x == size_y;
+    let no_ops = if point_group { 8 } else { 1 };
+
+    let mut canonical: Option<Vec<u8>> = None;
+
+    for op in 0..no_ops {
+        for dx in 0..size_x {
+            for dy in 0..size_y {
+                let transformed: Vec<u8> = (0..size_y).flat_map(|y| (0..latt.ncols()).map(move |x| (y, x)))
+                    .map(|(y, x)| {
+                        let (cell_x, s) = (x/n_cell_sites, x%n_cell_sites);
+
+                        // reflections of both axes and the transpose
+                        let cell_x = if op & 1 == 1 { size_x - 1 - cell_x } else { cell_x };
+                        let y = if op & 2 == 2 { size_y - 1 - y } else { y };
+                        let (cell_x, y) = if op & 4 == 4 { (y, cell_x) } else { (cell_x, y) };
+
+                        occupation[((y + dy)%size_y, ((cell_x + dx)%size_x)*n_cell_sites + s)]
+                    })
+                    .collect();
+
+                if canonical.as_ref().is_none_or(|c| transformed < *c) {
+                    canonical = Some(transformed);
+                }
+            }
+        }
+    }
+
+    canonical.unwrap()
+}
+
+/// Enumerate all distinct periodic occupations of the lattice of dip
+///
+/// The unit cells are the supercells with at most max_cell_sites sites
+/// which tile the lattice, in the Hermite normal form of their lattice
+/// vectors (a, 0) and (b, c) with 0 <= b < a in units of the lattice
+/// cells along x and y. This includes the sheared cells with b > 0,
+/// e.g. the checkerboard of the square lattice with a = 2, b = 1 and
+/// c = 1. Every site takes an occupation 0..=n_max. Occupations related
+/// by a lattice translation, and for the square geometry with
+/// size_x = size_y by a rotation or reflection, are only returned once,
+/// with the smallest unit cell.
+///
+/// Returns an error if the lattice boundary is not periodic, the
+/// potential is not uniform or the unit cells have more than
+/// MAX_PATTERNS occupations in total.
+pub fn enumerate_patterns(dip: &DipolarSystem, max_cell_sites: usize, n_max: u8) -> Result<Vec<PeriodicPattern>> {
+    let latt = &dip.latt;
+    if !dip.has_uniform_potential() {
+        return Err(Error::Unsupported("pattern enumeration requires a uniform potential"))
+    }
+
+    let n_cell_sites = latt.n_cell_sites();
+    let mut work = dip.clone();
+    let kernel = get_dd_kernel(&work)?;
+
+    // (a, b, c) of the supercells which contain the periods (size_x, 0) and (0, size_y)
+    let mut unit_cells: Vec<(usize, usize, usize)> = (1..latt.size_x + 1)
+        .filter(|a| latt.size_x.is_multiple_of(*a))
+        .flat_map(|a| (1..latt.size_y + 1)
+                  .filter(|c| latt.size_y.is_multiple_of(*c))
+                  .flat_map(move |c| (0..a)
+                            .filter(move |b| (latt.size_y/c*b).is_multiple_of(a))
+                            .map(move |b| (a, b, c))))
+        .filter(|(a, _, c)| a*n_cell_sites*c <= max_cell_sites)
+        .collect();
+    unit_cells.sort_by_key(|(a, _, c)| a*c);
+
+    let no_patterns = unit_cells.iter()
+        .try_fold(0usize, |total, (a, _, c)| {
+            u32::try_from(a*n_cell_sites*c).ok()
+                .and_then(|no_cell_sites| (n_max as usize + 1).checked_pow(no_cell_sites))
+                .and_then(|no_patterns| total.checked_add(no_patterns))
+        })
+        .filter(|&total| total <= MAX_PATTERNS);
+    if no_patterns.is_none() {
+        return Err(Error::Config(format!("more than {MAX_PATTERNS} patterns with max_cell_sites {max_cell_sites} \
+                                          and n_max {n_max}")))
+    }
+
+    let mut seen = HashSet::new();
+    let mut patterns = Vec::new();
+
+    for (a, b, c) in unit_cells {
+        let (cx, cy) = (a*n_cell_sites, c);
+        let no_patterns = (n_max as usize + 1).pow((cx*cy) as u32);
+
+        for code in 0..no_patterns {
+            // occupations of the unit cell as digits of code
+            let cell = DMatrix::from_fn(cy, cx, |ay, ax| {
+                ((code/(n_max as usize + 1).pow((ay*cx + ax) as u32))%(n_max as usize + 1)) as u8
+            });
+            // every row of unit cells is shifted by b cells along x
+            let occupation = DMatrix::from_fn(latt.size_y, latt.ncols(), |y, x| {
+                let cell_x = (x/n_cell_sites + a*latt.size_y - (y/c)*b)%a;
+                cell[(y%c, cell_x*n_cell_sites + x%n_cell_sites)]
+            });
+
+            if !seen.insert(get_canonical_occupation(&occupation, latt)) {
+                continue
+            }
+
+            work.occupation = occupation;
+            generate_dd_int_mat_fft(&mut work, &kernel)?;
+
+            patterns.push(PeriodicPattern {
+                occupation: work.occupation.clone(),
+                unit_cell: (cx, cy),
+                shear: b*n_cell_sites,
+                density: work.occupation.map(|n| n as f64).mean(),
+                energy_zero: get_energy(0., &work).total()/latt.no_sites() as f64,
+                stable_range: get_mu_inequality(&work),
+            });
+        }
+    }
+
+    Ok(patterns)
+}
+
+/// Get the ground state patterns along the mu axis in the atomic limit
+///
+/// Returns the index into patterns and the mu range of every pattern
+/// which has the lowest energy in a part of mu_range, ordered by mu.
+/// The energies are linear in mu, so the ground states are the lower
+/// envelope of the energy lines. Of patterns with the same energy the
+/// one with the lower density is taken.
+pub fn get_ground_state_ranges(patterns: &[PeriodicPattern], mu_range: (f64, f64)) -> Vec<(usize, (f64, f64))> {
+    let (mu_start, mu_end) = mu_range;
+
+    let lowest_at = |mu: f64| (0..patterns.len())
+        .min_by(|&a, &b| patterns[a].get_energy(mu).total_cmp(&patterns[b].get_energy(mu))
+                .then(patterns[a].density.total_cmp(&patterns[b].density)));
+
+    let Some(mut current) = lowest_at(mu_start) else { return Vec::new() };
+    let mut mu = mu_start;
+    let mut ranges = Vec::new();
+
+    loop {
+        let pattern = &patterns[current];
+
+        // the next ground state has a higher density and crosses first
+        let next = (0..patterns.len())
+            .filter(|&idx| patterns[idx].density > pattern.density)
+            .map(|idx| {
+                let crossing = (patterns[idx].energy_zero - pattern.energy_zero)
+                    /(patterns[idx].density - pattern.density);
+                (idx, crossing)
+            })
+            .filter(|&(_, crossing)| crossing >= mu)
+            .min_by(|a, b| a.1.total_cmp(&b.1)
+                    .then(patterns[b.0].density.total_cmp(&patterns[a.0].density)));
+
+        match next {
+            Some((idx, crossing)) if crossing < mu_end => {
+                if crossing > mu {
+                    ranges.push((current, (mu, crossing)));
+                }
+                current = idx;
+                mu = crossing;
+            },
+            _ => {
+                ranges.push((current, (mu, mu_end)));
+                return ranges
+            },
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use na::{DMatrix, DVector};
+
+use crate::dipolar::{DipolarSystem, TunnelingResult, get_m_coeff, get_stable_m_coeffs};
+
+/// Square sparse matrix in compressed sparse row (CSR) format
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CsrMatrix {
+    pub size: usize,
+    /// start of each row in col_indices and values, has size + 1 entries
+    pub row_offsets: Vec<usize>,
+    pub col_indices: Vec<usize>,
+    pub values: Vec<f64>,
+}
+
+impl CsrMatrix {
+    /// Create a CSR matrix from the (column, value) entries of each row
+    ///
+    /// Entries with a repeated column overwrite the previous value,
+    /// the same as setting the element of a dense matrix.
+    pub fn from_rows(rows: Vec<Vec<(usize, f64)>>) -> Self {
+        let size = rows.len();
+        let mut row_offsets = Vec::with_capacity(size + 1);
+        let mut col_indices = Vec::new();
+        let mut values = Vec::new();
+
+        row_offsets.push(0);
+
+        for mut row in rows {
+            // stable sort keeps the insertion order of repeated columns
+            row.sort_by_key(|(col, _)| *col);
+
+            for (col, val) in row {
+                assert!(col < size, "column index out of bounds");
+
+                if col_indices.len() > *row_offsets.last().unwrap()
+                    && *col_indices.last().unwrap() == col {
+                    *values.last_mut().unwrap() = val;
+                } else {
+                    col_indices.push(col);
+                    values.push(val);
+                }
+            }
+            row_offsets.push(col_indices.len());
+        }
+
+        CsrMatrix { size, row_offsets, col_indices, values }
+    }
+
+    /// Number of stored elements
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Matrix vector product
+    pub fn mul_vec(&self, v: &DVector<f64>) -> DVector<f64> {
+        assert!(v.len() == self.size, "vector length != matrix size");
+
+        DVector::from_fn(self.size, |row, _| {
+            (self.row_offsets[row]..self.row_offsets[row + 1])
+                .map(|k| self.values[k]*v[self.col_indices[k]])
+                .sum()
+        })
+    }
+
+    pub fn to_dense(&self) -> DMatrix<f64> {
+        let mut dense = DMatrix::zeros(self.size, self.size);
+
+        for row in 0..self.size {
+            for k in self.row_offsets[row]..self.row_offsets[row + 1] {
+                dense[(row, self.col_indices[k])] = self.values[k];
+            }
+        }
+
+        dense
+    }
+}
+
+/// Get the spin indices of the nearest neighbours of a site
+/// with the relative tunneling of the bonds, see get_hoppings
+fn get_neighbour_idx(spin_idx: usize, dip: &DipolarSystem) -> Vec<(usize, f64)> {
+    let latt = &dip.latt;
+    let (x, y) = latt.get_spin_pos(spin_idx);
+
+    dip.get_hoppings(x, y).into_iter()
+        .map(|(x_n, y_n, bond)| (latt.get_spin_idx(x_n, y_n), bond))
+        .collect()
+}
+
+/// Sparse version of generate_mat_m
+///
+/// # Parameters:
+/// * mu - chemical potential
+/// * t - tunneling
+pub fn generate_mat_m_sparse(mu: f64, t: f64, dip: &DipolarSystem) -> CsrMatrix {
+    let latt = &dip.latt;
+
+    let rows = (0..latt.no_sites()).map(|idx| {
+        let (x, y) = latt.get_spin_pos(idx);
+        let row_val = t*get_m_coeff(x, y, mu, dip);
+
+        // diagonal elements are always 1.
+        let mut row = vec![(idx, 1.)];
+        row.extend(get_neighbour_idx(idx, dip).into_iter().map(|(idx_n, bond)| (idx_n, row_val*bond)));
+        row
+    }).collect();
+
+    CsrMatrix::from_rows(rows)
+}
+
+/// Sparse version of the symmetrised hopping matrix S from get_tunneling_eigen
+///
+/// Returns None if mu lies outside the stability region of the occupation.
+pub fn generate_mat_s_sparse(mu: f64, dip: &DipolarSystem) -> Option<CsrMatrix> {
+    let latt = &dip.latt;
+    let coeffs = get_stable_m_coeffs(mu, dip)?;
+
+    let rows = (0..latt.no_sites()).map(|idx| {
+        get_neighbour_idx(idx, dip).into_iter()
+            .map(|(idx_n, bond)| (idx_n, (coeffs[idx]*coeffs[idx_n]).sqrt()*bond))
+            .collect()
+    }).collect();
+
+    Some(CsrMatrix::from_rows(rows))
+}
+
+/// Get the largest eigenvalue of the symmetric tridiagonal matrix with
+/// diagonal alpha and off-diagonal beta, together with the last
+/// component of its normalised eigenvector
+fn get_tridiagonal_max_eigen(alpha: &[f64], beta: &[f64]) -> (f64, f64) {
+    let steps = alpha.len();
+    let tridiag = DMatrix::from_fn(steps, steps, |i, j| {
+        if i == j {
+            alpha[i]
+        } else if i + 1 == j {
+            beta[i]
+        } else if j + 1 == i {
+            beta[j]
+        } else {
+            0.
+        }
+    });
+    let eigen = tridiag.symmetric_eigen();
+    let imax = eigen.eigenvalues.imax();
+
+    (eigen.eigenvalues[imax], eigen.eigenvectors[(steps - 1, imax)])
+}
+
+/// Largest eigenvalue of a symmetric sparse matrix using
+/// the Lanczos algorithm with full reorthogonalisation
+///
+/// The iteration stops when the residual of the largest Ritz pair
+/// is below tolerance relative to the Ritz value. The residual is
+/// checked at steps growing by a quarter, so that diagonalising the
+/// tridiagonal matrix costs about as much as a single diagonalisation
+/// of the last one.
+///
+/// Returns the largest Ritz value with the residual norm of its Ritz
+/// pair and the number of Lanczos steps, or None if the iteration has
+/// not converged after max_iter steps.
+pub fn lanczos_max_eigenvalue(mat: &CsrMatrix, tolerance: f64, max_iter: usize) -> Option<(f64, f64, usize)> {
+    let size = mat.size;
+    let max_steps = max_iter.min(size).max(1);
+
+    // the leading eigenvector of a nonnegative matrix has a nonzero
+    // overlap with any positive vector, the varying entries avoid a
+    // vanishing overlap for the signed bonds of twisted boundaries
+    let start = DVector::from_fn(size, |idx, _| 1. + (idx as f64*0.618_033_988_749_895).fract());
+    let mut basis = vec![start.normalize()];
+    let mut alpha: Vec<f64> = Vec::new();
+    let mut beta: Vec<f64> = Vec::new();
+    let mut next_check = 1;
+
+    loop {
+        let v = basis.last().unwrap();
+        let mut w = mat.mul_vec(v);
+        alpha.push(w.dot(v));
+
+        for b in &basis {
+            let overlap = w.dot(b);
+            w -= b*overlap;
+        }
+
+        let steps = alpha.len();
+        let w_norm = w.norm();
+        // the Krylov space is invariant, so the Ritz values are exact
+        let exhausted = steps == size || w_norm == 0.;
+
+        if exhausted || steps >= next_check || steps >= max_steps {
+            let (ritz, last_component) = get_tridiagonal_max_eigen(&alpha, &beta);
+
+            // residual norm of the Ritz pair |S y - ritz y|
+            let residual = if exhausted { 0. } else { w_norm*last_component.abs() };
+            if residual < tolerance*ritz.abs() || exhausted {
+                return Some((ritz, residual, steps))
+            }
+            if steps >= max_steps {
+                return None
+            }
+            next_check = steps + steps.div_ceil(4);
+        }
+
+        beta.push(w_norm);
+        basis.push(w/w_norm);
+    }
+}
+
+/// Find smallest tunneling where det(M)=0 using the sparse
+/// symmetrised hopping matrix, see get_tunneling_eigen.
+///
+/// Memory scales with the number of sites times the number of
+/// Lanczos steps instead of the square of the number of sites.
+///
+/// Returns Found with the residual norm of the Lanczos iteration and
+/// its number of steps, NotFound if mu lies outside the stability
+/// region of the occupation or no positive critical tunneling exists,
+/// and NotConverged if the Lanczos iteration has not converged after
+/// max_iter steps, see lanczos_max_eigenvalue.
+pub fn get_tunneling_sparse(mu: f64, dip: &DipolarSystem,
+                            tolerance: f64, max_iter: usize) -> TunnelingResult {
+    let Some(s_mat) = generate_mat_s_sparse(mu, dip) else {
+        return TunnelingResult::NotFound
+    };
+
+    match lanczos_max_eigenvalue(&s_mat, tolerance, max_iter) {
+        Some((lambda_max, residual, iterations)) if lambda_max > 0. =>
+            TunnelingResult::Found { tunneling: 1./lambda_max, residual, iterations },
+        Some(_) => TunnelingResult::NotFound,
+        None => TunnelingResult::NotConverged,
+    }
+}
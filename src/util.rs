@@ -1,50 +1,379 @@
-use na::{DVector, Scalar};
+use na::{DMatrix, DVector, Scalar};
 use std::fs;
 use std::path::Path;
+use std::thread;
 use serde::ser;
 use serde::Deserialize;
-use super::dipolar::Pattern;
+use std::f64::consts::PI;
+use super::diagram::DiagramParams;
+use super::dipolar::{Hopping, Pattern, Potential, Solver, SweepParams};
+use super::disorder::Disorder;
+use super::phase::PhaseMapGrid;
+use super::search::EnumerationParams;
+use super::error::{Error, Result};
+use super::lattice::{Boundary, Geometry, Stacking};
 
 /// Basic linspace function
 /// 
-/// Doesn't perform any checks, use with
-/// care. Will silently convert num and idx to f64 
-/// even if num cannot be represented as f64
+/// Will silently convert num and idx to f64 
+/// even if num cannot be represented as f64.
+/// Returns an empty vector for num = 0 and start for
+/// num = 1.
 pub fn linspace(start: f64, stop: f64, num: usize, endpoint: bool) -> DVector<f64> {
 
     let denom = if endpoint {
-        num - 1
+        num.saturating_sub(1).max(1)
     } else {
-        num
+        num.max(1)
     };
 
     let delta = (stop - start)/(denom as f64);
     DVector::from_iterator(num, (0..num).map(|idx| (idx as f64)*delta + start))
 }
 
+/// Get the points of a sweep axis, num evenly spaced points from
+/// start to end, or only start if end is not given or num < 2
+pub fn get_sweep_axis(start: f64, end: Option<f64>, num: usize) -> DVector<f64> {
+    match end {
+        Some(end) if num > 1 => linspace(start, end, num, true),
+        _ => DVector::from_element(1, start),
+    }
+}
+
+/// Map f over values in parallel, keeping the order of values
+///
+/// The values are split into num_threads contiguous chunks, each
+/// evaluated on its own thread, so the result is identical to
+/// the serial map. num_threads = 0 uses all available cores.
+pub fn parallel_map<T, F>(values: &DVector<f64>, num_threads: usize, f: F) -> DVector<T>
+where T: Scalar + Send,
+      F: Fn(f64) -> T + Sync,
+{
+    let num_threads = if num_threads == 0 {
+        thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        num_threads
+    };
+
+    if num_threads == 1 || values.len() < 2 {
+        return values.map(f)
+    }
+
+    let chunk_size = values.len().div_ceil(num_threads);
+    let f = &f;
+
+    let results: Vec<T> = thread::scope(|s| {
+        let handles: Vec<_> = values.as_slice()
+            .chunks(chunk_size)
+            .map(|chunk| s.spawn(move || chunk.iter().map(|v| f(*v)).collect::<Vec<T>>()))
+            .collect();
+
+        handles.into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    });
+
+    DVector::from_vec(results)
+}
+
 /// Save a DVector<T> into a json file
-pub fn save_vector_json<T, P>(filename: P, values: DVector<T>)
+pub fn save_vector_json<T, P>(filename: P, values: DVector<T>) -> Result<()>
 where P: AsRef<Path>, 
       T: Scalar + ser::Serialize,
 {
-    // Convert the vector to a JSON string
-    let json_string = serde_json::to_string(&values).unwrap();                                         
+    save_json(filename, &values)
+}
+
+/// Save any serializable value into a json file
+pub fn save_json<T, P>(filename: P, value: &T) -> Result<()>
+where P: AsRef<Path>,
+      T: ser::Serialize + ?Sized,
+{
+    let json_string = serde_json::to_string(value)?;
 
-    match fs::write(filename, json_string) {
-        Ok(_) => (),
-        Err(s) => println!("Error writing to file: {s}"),
-    }   
+    fs::write(filename, json_string)?;
+    Ok(())
+}
+
+/// Error of an unknown config string
+fn unknown(key: &str, value: &str) -> Error {
+    Error::Config(format!("unknown {key} \"{value}\""))
+}
+
+/// Error unless value is finite and positive
+fn check_positive(key: &str, value: f64) -> Result<()> {
+    if !(value.is_finite() && value > 0.) {
+        return Err(Error::Config(format!("{key} = {value} needs to be finite and positive")))
+    }
+    Ok(())
 }
 
 /// Parse pattern from a config string
-pub fn parse_pattern_str(pattern_str: String) -> Pattern {
+///
+/// fillings are the occupations of the occupied and empty sites,
+/// search_mu, n_max and seed are the parameters of the "search" pattern
+pub fn parse_pattern_str(pattern_str: String, fillings: (u8, u8),
+                         search_mu: f64, n_max: u8, seed: u64) -> Result<Pattern> {
     let patt = match pattern_str.to_lowercase().as_ref() {
-        "filled" => Pattern::Filled,
-        "cb" => Pattern::CB,
-        "hstripe" => Pattern::HStripe,
-        _ => Pattern::Filled
+        "filled" => Pattern::Filled(fillings.0),
+        "cb" => Pattern::CB(fillings.0, fillings.1),
+        "hstripe" => Pattern::HStripe(fillings.0, fillings.1),
+        "search" => Pattern::Search { mu: search_mu, n_max, seed },
+        _ => return Err(unknown("pattern", &pattern_str))
     };
-    patt
+    Ok(patt)
+}
+
+/// Parse critical tunneling solver from a config string
+///
+/// bracket_step and tolerance are the parameters of the "bisection" solver,
+/// which need to be finite and positive, and sparse_tolerance and
+/// sparse_max_iter those of the "sparse" solver, which need to be
+/// finite and positive and at least 1
+pub fn parse_solver_str(solver_str: &str, bracket_step: f64, tolerance: f64,
+                        sparse_tolerance: f64, sparse_max_iter: usize) -> Result<Solver> {
+    let solver = match solver_str.to_lowercase().as_ref() {
+        "scan" => Solver::Scan,
+        "bisection" => {
+            check_positive("bracket_step", bracket_step)?;
+            check_positive("tolerance", tolerance)?;
+            Solver::Bisection { bracket_step, tolerance }
+        },
+        "eigen" => Solver::Eigen,
+        "momentum" => Solver::Momentum,
+        "sparse" => {
+            check_positive("sparse_tolerance", sparse_tolerance)?;
+            if sparse_max_iter == 0 {
+                return Err(Error::Config("sparse_max_iter needs to be at least 1".to_string()))
+            }
+            Solver::Sparse { tolerance: sparse_tolerance, max_iter: sparse_max_iter }
+        },
+        _ => return Err(unknown("solver", solver_str))
+    };
+    Ok(solver)
+}
+
+/// Parse lattice geometry from a config string
+pub fn parse_geometry_str(geometry_str: &str) -> Result<Geometry> {
+    let geometry = match geometry_str.to_lowercase().as_ref() {
+        "square" => Geometry::Square,
+        "triangular" => Geometry::Triangular,
+        "honeycomb" => Geometry::Honeycomb,
+        "kagome" => Geometry::Kagome,
+        _ => return Err(unknown("geometry", geometry_str))
+    };
+    Ok(geometry)
+}
+
+/// Parse lattice boundary conditions from a config string
+///
+/// twist is (twist_x, twist_y) of the "twisted" boundary
+pub fn parse_boundary_str(boundary_str: &str, twist: (bool, bool)) -> Result<Boundary> {
+    let boundary = match boundary_str.to_lowercase().as_ref() {
+        "periodic" => Boundary::Periodic,
+        "open" => Boundary::Open,
+        "cylinder_x" => Boundary::CylinderX,
+        "cylinder_y" => Boundary::CylinderY,
+        "twisted" => Boundary::Twisted { twist_x: twist.0, twist_y: twist.1 },
+        _ => return Err(unknown("boundary", boundary_str))
+    };
+    Ok(boundary)
+}
+
+/// Load a matrix from a JSON file of rows
+pub fn load_matrix_json<P: AsRef<Path>>(filename: P) -> Result<DMatrix<f64>> {
+    let file_str = fs::read_to_string(filename)?;
+    let rows: Vec<Vec<f64>> = serde_json::from_str(&file_str)?;
+
+    let ncols = rows.first().map_or(0, |row| row.len());
+    if let Some(row) = rows.iter().find(|row| row.len() != ncols) {
+        return Err(Error::Shape { name: "matrix row", expected: (1, ncols), found: (1, row.len()) })
+    }
+
+    Ok(DMatrix::from_fn(rows.len(), ncols, |row, col| rows[row][col]))
+}
+
+/// Load the config from a TOML file
+pub fn load_config<P: AsRef<Path>>(filename: P) -> Result<Config> {
+    let config_str = fs::read_to_string(filename)?;
+
+    toml::from_str(&config_str).map_err(|err| Error::Config(err.to_string()))
+}
+
+/// Parse the site-dependent potential from a config string
+///
+/// trap_strength and trap_centre are the parameters of the "harmonic"
+/// potential, potential_map the JSON file of the "map" potential,
+/// see load_matrix_json
+pub fn parse_potential_str(potential_str: &str, trap_strength: f64, trap_centre: Option<(f64, f64)>,
+                           potential_map: Option<&str>) -> Result<Potential> {
+    let potential = match potential_str.to_lowercase().as_ref() {
+        "uniform" => Potential::Uniform,
+        "harmonic" => Potential::Harmonic { strength: trap_strength, centre: trap_centre },
+        "map" => {
+            let filename = potential_map
+                .ok_or_else(|| Error::Config("potential_map is not given".to_string()))?;
+            Potential::Map(load_matrix_json(filename)?)
+        },
+        _ => return Err(unknown("potential", potential_str))
+    };
+    Ok(potential)
+}
+
+/// Parse the stacking of layers from a config string
+pub fn parse_stacking_str(stacking_str: &str, layers: usize, layer_spacing: f64) -> Result<Stacking> {
+    let stacking = match stacking_str.to_lowercase().as_ref() {
+        "open" => Stacking::Open(layers, layer_spacing),
+        "periodic" => Stacking::Periodic(layers, layer_spacing),
+        "cubic" => Stacking::Cubic,
+        _ => return Err(unknown("stacking", stacking_str))
+    };
+    Ok(stacking)
+}
+
+/// Get the sweep parameters of a config, see simulation_sweep
+///
+/// The ranges and sizes of the config include their end and the angles
+/// are in fractions of PI. Returns an error if a config string is not
+/// known or a value is not supported.
+pub fn parse_config(config: Config) -> Result<SweepParams> {
+    let n_max: u8 = config.n_max.try_into()
+        .map_err(|_| Error::Config(format!("n_max = {} is larger than {}", config.n_max, u8::MAX)))?;
+    if !matches!(config.extrapolation_power, 1 | 2) {
+        return Err(Error::Config(format!("extrapolation_power = {} needs to be 1 or 2",
+                                         config.extrapolation_power)))
+    }
+    let u_onsite = config.u_onsite;
+
+    Ok(SweepParams {
+        pattern: parse_pattern_str(config.pattern, config.fillings, config.search_mu, n_max, config.seed)?,
+        int_ranges: (config.range_start, config.range_end + 1),
+        system_sizes: (config.size_start, config.size_end + 1),
+        system_sizes_y: config.size_y_start.map(|start| (start, config.size_y_end.unwrap_or(start) + 1)),
+        geometry: parse_geometry_str(&config.geometry)?,
+        boundary: parse_boundary_str(&config.boundary, (config.twist_x, config.twist_y))?,
+        stacking: parse_stacking_str(&config.stacking, config.layers, config.layer_spacing)?,
+        thetas: get_sweep_axis(config.theta, config.theta_end, config.theta_steps)*PI,
+        phis: get_sweep_axis(config.phi, config.phi_end, config.phi_steps)*PI,
+        u_onsite,
+        solver: parse_solver_str(&config.solver, config.bracket_step, config.tolerance,
+                                 config.sparse_tolerance, config.sparse_max_iter)?,
+        num_threads: config.threads,
+        hopping: Hopping::new(config.tunneling_x, config.tunneling_y, config.tunneling_z, config.tunneling_nnn),
+        bond_charge: config.bond_charge,
+        potential: parse_potential_str(&config.potential, config.trap_strength, config.trap_centre,
+                                       config.potential_map.as_deref())?,
+        disorder: config.disorder.then(|| {
+            Disorder::new(config.disorder_onsite, config.disorder_tunneling, config.disorder_realisations, config.seed)
+        }),
+        lattice_sum: config.lattice_sum,
+        phase_map: config.phase_map.then(|| {
+            PhaseMapGrid::new((config.phase_mu_start, config.phase_mu_end.unwrap_or(u_onsite)),
+                              config.phase_t_max, config.phase_points, config.n_max)
+        }),
+        enumeration: config.enumerate.then(|| {
+            EnumerationParams::new(config.enumerate_cell_sites, n_max,
+                                   (config.enumerate_mu_start, config.enumerate_mu_end.unwrap_or(u_onsite)))
+        }),
+        no_lobes: config.no_lobes,
+        diagram: config.phase_diagram.then(|| {
+            DiagramParams::new((config.diagram_mu_start, config.diagram_mu_end.unwrap_or(u_onsite)),
+                               config.diagram_points, n_max)
+        }),
+        extrapolation_power: config.extrapolate.then_some(config.extrapolation_power),
+    })
+}
+
+fn default_fillings() -> (u8, u8) {
+    (1, 0)
+}
+
+fn default_no_lobes() -> u8 {
+    1
+}
+
+fn default_tunneling() -> f64 {
+    1.
+}
+
+fn default_disorder_realisations() -> usize {
+    10
+}
+
+fn default_potential() -> String {
+    "uniform".to_string()
+}
+
+fn default_angle_steps() -> usize {
+    1
+}
+
+fn default_extrapolation_power() -> u32 {
+    1
+}
+
+fn default_diagram_points() -> usize {
+    100
+}
+
+fn default_phase_t_max() -> f64 {
+    1.
+}
+
+fn default_phase_points() -> usize {
+    40
+}
+
+fn default_enumerate_cell_sites() -> usize {
+    8
+}
+
+fn default_n_max() -> usize {
+    3
+}
+
+fn default_stacking() -> String {
+    "open".to_string()
+}
+
+fn default_layers() -> usize {
+    1
+}
+
+fn default_layer_spacing() -> f64 {
+    1.
+}
+
+fn default_boundary() -> String {
+    "periodic".to_string()
+}
+
+fn default_geometry() -> String {
+    "square".to_string()
+}
+
+fn default_solver() -> String {
+    "scan".to_string()
+}
+
+fn default_threads() -> usize {
+    1
+}
+
+fn default_bracket_step() -> f64 {
+    1e-2
+}
+
+fn default_tolerance() -> f64 {
+    1e-6
+}
+
+fn default_sparse_tolerance() -> f64 {
+    1e-10
+}
+
+fn default_sparse_max_iter() -> usize {
+    500
 }
 
 /// Pattern struct
@@ -52,6 +381,12 @@ pub fn parse_pattern_str(pattern_str: String) -> Pattern {
 pub struct Config {
    /// pattern to simulate
    pub pattern: String,
+   /// occupations of the occupied and empty sites of the pattern
+   #[serde(default = "default_fillings")]
+   pub fillings: (u8, u8),
+   /// number of successive lobes, each with one more particle per site
+   #[serde(default = "default_no_lobes")]
+   pub no_lobes: u8,
    /// interaction range start
    pub range_start: usize,
    /// interaction range end
@@ -60,10 +395,155 @@ pub struct Config {
    pub size_start: usize,
    /// size end
    pub size_end: usize,
+   /// lattice geometry
+   #[serde(default = "default_geometry")]
+   pub geometry: String,
+   /// lattice boundary conditions
+   #[serde(default = "default_boundary")]
+   pub boundary: String,
+   /// flip the sign of the tunneling across the x boundary of the twisted boundary
+   #[serde(default)]
+   pub twist_x: bool,
+   /// flip the sign of the tunneling across the y boundary of the twisted boundary
+   #[serde(default)]
+   pub twist_y: bool,
+   /// stacking of layers along z
+   #[serde(default = "default_stacking")]
+   pub stacking: String,
+   /// number of layers
+   #[serde(default = "default_layers")]
+   pub layers: usize,
+   /// distance between neighbouring layers
+   #[serde(default = "default_layer_spacing")]
+   pub layer_spacing: f64,
+   /// size along y start, square lattices if not given
+   pub size_y_start: Option<usize>,
+   /// size along y end
+   pub size_y_end: Option<usize>,
    /// theta (in fraction of PI)
    pub theta: f64,
+   /// theta end (in fraction of PI), theta is fixed if not given
+   pub theta_end: Option<f64>,
+   /// number of theta points
+   #[serde(default = "default_angle_steps")]
+   pub theta_steps: usize,
    /// phi (in fraction of PI)
    pub phi: f64, 
+   /// phi end (in fraction of PI), phi is fixed if not given
+   pub phi_end: Option<f64>,
+   /// number of phi points
+   #[serde(default = "default_angle_steps")]
+   pub phi_steps: usize,
    /// onsite interaction
    pub u_onsite: f64,
+   /// tunneling along x relative to the reference tunneling t
+   #[serde(default = "default_tunneling")]
+   pub tunneling_x: f64,
+   /// tunneling along y relative to the reference tunneling t
+   #[serde(default = "default_tunneling")]
+   pub tunneling_y: f64,
+   /// tunneling between layers relative to the reference tunneling t
+   #[serde(default = "default_tunneling")]
+   pub tunneling_z: f64,
+   /// next-nearest neighbour tunneling relative to the reference tunneling t
+   #[serde(default)]
+   pub tunneling_nnn: f64,
+   /// occupation dependence of the tunneling, see DipolarSystem::bond_charge
+   #[serde(default)]
+   pub bond_charge: f64,
+   /// site-dependent potential
+   #[serde(default = "default_potential")]
+   pub potential: String,
+   /// strength of the harmonic trap, the potential is trap_strength*r^2
+   #[serde(default)]
+   pub trap_strength: f64,
+   /// centre of the harmonic trap, the centre of the lattice if not given
+   pub trap_centre: Option<(f64, f64)>,
+   /// JSON file with the potential of every site
+   pub potential_map: Option<String>,
+   /// average the lobes over disorder realisations
+   #[serde(default)]
+   pub disorder: bool,
+   /// width of the uniform onsite energy disorder
+   #[serde(default)]
+   pub disorder_onsite: f64,
+   /// width of the uniform relative bond tunneling disorder
+   #[serde(default)]
+   pub disorder_tunneling: f64,
+   /// number of disorder realisations, with seeds seed, seed + 1, ...
+   #[serde(default = "default_disorder_realisations")]
+   pub disorder_realisations: usize,
+   /// critical tunneling solver
+   #[serde(default = "default_solver")]
+   pub solver: String,
+   /// tunneling step bracketing the first zero of det(M) in the bisection solver
+   #[serde(default = "default_bracket_step")]
+   pub bracket_step: f64,
+   /// width of the final bracket of the bisection solver
+   #[serde(default = "default_tolerance")]
+   pub tolerance: f64,
+   /// residual tolerance of the Lanczos iteration of the sparse solver,
+   /// relative to the largest eigenvalue
+   #[serde(default = "default_sparse_tolerance")]
+   pub sparse_tolerance: f64,
+   /// largest number of Lanczos steps of the sparse solver
+   #[serde(default = "default_sparse_max_iter")]
+   pub sparse_max_iter: usize,
+   /// number of threads, 0 uses all available cores
+   #[serde(default = "default_threads")]
+   pub threads: usize,
+   /// use the complete periodic lattice sum of the d-d interaction
+   #[serde(default)]
+   pub lattice_sum: bool,
+   /// compute the Gutzwiller phase map
+   #[serde(default)]
+   pub phase_map: bool,
+   /// phase map mu start
+   #[serde(default)]
+   pub phase_mu_start: f64,
+   /// phase map mu end, u_onsite if not given
+   pub phase_mu_end: Option<f64>,
+   /// phase map largest tunneling
+   #[serde(default = "default_phase_t_max")]
+   pub phase_t_max: f64,
+   /// number of phase map points along mu and t
+   #[serde(default = "default_phase_points")]
+   pub phase_points: usize,
+   /// largest occupation of the local Fock space and of the search, at most 255
+   #[serde(default = "default_n_max")]
+   pub n_max: usize,
+   /// enumerate the periodic patterns and their ground state mu ranges
+   #[serde(default)]
+   pub enumerate: bool,
+   /// largest unit cell of the enumerated patterns
+   #[serde(default = "default_enumerate_cell_sites")]
+   pub enumerate_cell_sites: usize,
+   /// enumeration mu start
+   #[serde(default)]
+   pub enumerate_mu_start: f64,
+   /// enumeration mu end, u_onsite if not given
+   pub enumerate_mu_end: Option<f64>,
+   /// assemble the phase diagram of the candidate patterns
+   #[serde(default)]
+   pub phase_diagram: bool,
+   /// phase diagram mu start
+   #[serde(default)]
+   pub diagram_mu_start: f64,
+   /// phase diagram mu end, u_onsite if not given
+   pub diagram_mu_end: Option<f64>,
+   /// number of phase diagram mu points
+   #[serde(default = "default_diagram_points")]
+   pub diagram_points: usize,
+   /// extrapolate the lobe tips of the system sizes to infinite size
+   #[serde(default)]
+   pub extrapolate: bool,
+   /// the tips are fitted linearly in 1/L^extrapolation_power, 1 or 2
+   #[serde(default = "default_extrapolation_power")]
+   pub extrapolation_power: u32,
+   /// chemical potential of the ground state search
+   #[serde(default)]
+   pub search_mu: f64,
+   /// random seed
+   #[serde(default)]
+   pub seed: u64,
 }
\ No newline at end of file